@@ -0,0 +1,300 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+
+use conrecord::{ConRecord, HasConData, HasTcpState, TIME_STAMP_REDUCTION_FACTOR};
+use system::SystemData;
+use utils::Histogram;
+use {ReleaseCause, TcpState};
+
+/// latency distributions of a group of connections, in cycles
+#[derive(Clone, Default)]
+pub struct LatencyStats {
+    pub connections: u64,
+    /// from SynSent (client) or SynReceived (server) to Established
+    pub syn_to_established: Histogram,
+    /// from Established to FinWait1 (active close) or CloseWait (passive close)
+    pub established_to_fin: Histogram,
+    /// from the first to the last state transition
+    pub lifetime: Histogram,
+}
+
+impl LatencyStats {
+    fn record(&mut self, latencies: &Latencies) {
+        self.connections += 1;
+        if let Some(l) = latencies.syn_to_established {
+            self.syn_to_established.record(l);
+        }
+        if let Some(l) = latencies.established_to_fin {
+            self.established_to_fin.record(l);
+        }
+        if let Some(l) = latencies.lifetime {
+            self.lifetime.record(l);
+        }
+    }
+
+    fn write_to(&self, out: &mut String, system_data: Option<&SystemData>) -> fmt::Result {
+        writeln!(out, "  connections= {}", self.connections)?;
+        for (name, histogram) in &[
+            ("syn -> established", &self.syn_to_established),
+            ("established -> fin", &self.established_to_fin),
+            ("lifetime", &self.lifetime),
+        ] {
+            if histogram.count() > 0 {
+                match system_data {
+                    Some(system_data) => {
+                        writeln!(out, "  {}: {}", name, histogram.percentiles().to_durations(system_data))?
+                    }
+                    None => writeln!(out, "  {}: {} (cycles)", name, histogram.percentiles())?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// result of analyze
+#[derive(Clone, Default)]
+pub struct ConnectionReport {
+    pub all: LatencyStats,
+    /// only causes which occurred, in the order of ReleaseCause
+    pub by_release_cause: Vec<(ReleaseCause, LatencyStats)>,
+    pub by_server_index: BTreeMap<u8, LatencyStats>,
+    /// connections without state transitions
+    pub without_stamps: u64,
+    /// connections whose state history is truncated, see HasTcpState::dropped_states, they are not counted in
+    /// the transition latencies syn_to_established and established_to_fin
+    pub truncated: u64,
+}
+
+impl ConnectionReport {
+    /// human readable report, latencies are converted to Duration if system_data is given
+    pub fn format(&self, system_data: Option<&SystemData>) -> String {
+        let mut out = String::new();
+        self.write_to(&mut out, system_data).unwrap();
+        out
+    }
+
+    pub fn print(&self, system_data: Option<&SystemData>) {
+        print!("{}", self.format(system_data));
+    }
+
+    fn write_to(&self, out: &mut String, system_data: Option<&SystemData>) -> fmt::Result {
+        writeln!(
+            out,
+            "all connections ({} without state transitions, {} with truncated state history):",
+            self.without_stamps, self.truncated
+        )?;
+        self.all.write_to(out, system_data)?;
+        for (cause, stats) in &self.by_release_cause {
+            writeln!(out, "release cause {:?}:", cause)?;
+            stats.write_to(out, system_data)?;
+        }
+        for (index, stats) in &self.by_server_index {
+            writeln!(out, "server index {}:", index)?;
+            stats.write_to(out, system_data)?;
+        }
+        Ok(())
+    }
+}
+
+/// latencies of a single connection in cycles
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Latencies {
+    pub syn_to_established: Option<u64>,
+    pub established_to_fin: Option<u64>,
+    pub lifetime: Option<u64>,
+}
+
+/// the states after the start state together with the time stamp of the transition into the state, the
+/// transitions dropped from a full state history are missing after the first state
+pub fn state_stamps(c: &ConRecord) -> Vec<(TcpState, u64)> {
+    let base_stamp = match c.get_first_stamp() {
+        Some(stamp) => stamp,
+        None => return vec![],
+    };
+    let stamps = Some(base_stamp).into_iter().chain(
+        c.deltas_to_base_stamp()
+            .into_iter()
+            .map(|d| base_stamp + d as u64 * TIME_STAMP_REDUCTION_FACTOR),
+    );
+    c.states().into_iter().skip(1).zip(stamps).collect()
+}
+
+/// the latencies of c, without the transition latencies if transitions were dropped from the state history of c
+pub fn latencies(c: &ConRecord) -> Latencies {
+    let stamps = state_stamps(c);
+    let lifetime = match (stamps.first(), stamps.last()) {
+        (Some(first), Some(last)) if last.1 >= first.1 => Some(last.1 - first.1),
+        _ => None,
+    };
+    if c.dropped_states() > 0 {
+        return Latencies {
+            lifetime,
+            ..Default::default()
+        };
+    }
+    let first = |pred: &dyn Fn(TcpState) -> bool| stamps.iter().find(|(s, _)| pred(*s)).map(|(_, t)| *t);
+    let syn = first(&|s| s == TcpState::SynSent || s == TcpState::SynReceived);
+    let established = first(&|s| s == TcpState::Established);
+    let fin = first(&|s| s == TcpState::FinWait1 || s == TcpState::CloseWait);
+    let diff = |from: Option<u64>, to: Option<u64>| match (from, to) {
+        (Some(from), Some(to)) if to >= from => Some(to - from),
+        _ => None,
+    };
+    Latencies {
+        syn_to_established: diff(syn, established),
+        established_to_fin: diff(established, fin),
+        lifetime,
+    }
+}
+
+/// collects the latency distributions of the connections, in total and grouped by release cause and server index
+pub fn analyze<'a, I: IntoIterator<Item = &'a ConRecord>>(records: I) -> ConnectionReport {
+    let mut report = ConnectionReport::default();
+    let mut by_cause: Vec<Option<LatencyStats>> = vec![None; ReleaseCause::MaxCauses as usize + 1];
+    for c in records {
+        if c.get_first_stamp().is_none() {
+            report.without_stamps += 1;
+        }
+        if c.dropped_states() > 0 {
+            report.truncated += 1;
+        }
+        let latencies = latencies(c);
+        report.all.record(&latencies);
+        by_cause[c.release_cause() as usize]
+            .get_or_insert_with(LatencyStats::default)
+            .record(&latencies);
+        report
+            .by_server_index
+            .entry(c.server_index())
+            .or_insert_with(LatencyStats::default)
+            .record(&latencies);
+    }
+    report.by_release_cause = by_cause
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, stats)| stats.map(|stats| (ReleaseCause::from(i as u8), stats)))
+        .collect();
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use recstore::{RecordStore, Storable};
+    use TcpRole;
+
+    #[test]
+    fn latencies_of_record() {
+        let mut c = ConRecord::new();
+        c.init(TcpRole::Client, 1, None);
+        assert_eq!(latencies(&c), Latencies::default());
+        assert!(state_stamps(&c).is_empty());
+
+        c.push_state(TcpState::SynSent);
+        c.push_state(TcpState::Established);
+        c.push_state(TcpState::FinWait1);
+        c.push_state(TcpState::FinWait2);
+        let stamps = state_stamps(&c);
+        assert_eq!(
+            stamps.iter().map(|s| s.0).collect::<Vec<_>>(),
+            vec![
+                TcpState::SynSent,
+                TcpState::Established,
+                TcpState::FinWait1,
+                TcpState::FinWait2
+            ]
+        );
+        assert_eq!(stamps[0].1, c.get_first_stamp().unwrap());
+        assert_eq!(stamps[3].1, c.get_last_stamp().unwrap());
+        let l = latencies(&c);
+        assert_eq!(l.syn_to_established, Some(stamps[1].1 - stamps[0].1));
+        assert_eq!(l.established_to_fin, Some(stamps[2].1 - stamps[1].1));
+        assert_eq!(l.lifetime, Some(stamps[3].1 - stamps[0].1));
+    }
+
+    #[test]
+    fn truncated_state_history() {
+        let mut c = ConRecord::new();
+        c.init(TcpRole::Server, 80, Some((0x0a00_0001, 1000)));
+        c.push_state_at(TcpState::SynReceived, 1000);
+        c.push_state_at(TcpState::Established, 2000);
+        for i in 0..3 {
+            c.push_state_at(TcpState::Established, 3000 + i * 1000);
+            c.push_state_at(TcpState::CloseWait, 3500 + i * 1000);
+        }
+        c.push_state_at(TcpState::LastAck, 9000);
+        assert_eq!(c.dropped_states(), 2);
+        assert_eq!(state_stamps(&c).len(), 7);
+        let l = latencies(&c);
+        assert_eq!(l.syn_to_established, None);
+        assert_eq!(l.established_to_fin, None);
+        assert_eq!(l.lifetime, Some(8000));
+
+        let mut complete = ConRecord::new();
+        complete.init(TcpRole::Server, 80, Some((0x0a00_0001, 1001)));
+        complete.push_state_at(TcpState::SynReceived, 1000);
+        complete.push_state_at(TcpState::Established, 2000);
+        let report = analyze(vec![&c, &complete]);
+        assert_eq!(report.all.connections, 2);
+        assert_eq!(report.truncated, 1);
+        assert_eq!(report.all.syn_to_established.count(), 1);
+        assert_eq!(report.all.lifetime.count(), 2);
+        assert!(report
+            .format(None)
+            .starts_with("all connections (0 without state transitions, 1 with truncated state history):"));
+    }
+
+    #[test]
+    fn analyze_store() {
+        let mut store = RecordStore::<ConRecord>::with_capacity(16);
+        for i in 0..10u8 {
+            let slot = store.get_next_slot();
+            let c = store.get_mut(slot);
+            c.init(TcpRole::Server, 80, Some((0x0a00_0001, 1000 + i as u16)));
+            c.set_server_index(i % 2);
+            c.push_state(TcpState::SynReceived);
+            if i < 8 {
+                c.push_state(TcpState::Established);
+                c.push_state(TcpState::CloseWait);
+                c.push_state(TcpState::LastAck);
+                c.set_release_cause(ReleaseCause::PassiveClose);
+            } else {
+                c.set_release_cause(ReleaseCause::Timeout);
+            }
+        }
+        // no state transitions
+        store.get_next_slot();
+
+        let report = analyze(store.iter());
+        assert_eq!(report.all.connections, 11);
+        assert_eq!(report.without_stamps, 1);
+        assert_eq!(report.all.syn_to_established.count(), 8);
+        assert_eq!(report.all.established_to_fin.count(), 8);
+        assert_eq!(report.all.lifetime.count(), 10);
+        assert_eq!(
+            report
+                .by_release_cause
+                .iter()
+                .map(|(c, s)| (*c, s.connections))
+                .collect::<Vec<_>>(),
+            vec![
+                (ReleaseCause::Unknown, 1),
+                (ReleaseCause::Timeout, 2),
+                (ReleaseCause::PassiveClose, 8)
+            ]
+        );
+        assert_eq!(report.by_release_cause[1].1.syn_to_established.count(), 0);
+        assert_eq!(report.by_server_index[&0].connections, 6);
+        assert_eq!(report.by_server_index[&1].connections, 5);
+
+        let system_data = SystemData {
+            cpu_clock: 2_000_000_000,
+            topology: Default::default(),
+        };
+        let text = report.format(Some(&system_data));
+        assert!(text.contains("release cause PassiveClose:\n  connections= 8\n  syn -> established: count= 8"));
+        assert!(text.contains("server index 1:"));
+        assert!(report.format(None).contains("(cycles)"));
+    }
+}
@@ -0,0 +1,69 @@
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::_rdtsc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// source of time stamps for timers and tasks, time is measured in cycles
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+/// the default clock, reads the time stamp counter of the cpu
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TscClock;
+
+impl Clock for TscClock {
+    #[cfg(target_arch = "x86_64")]
+    #[inline]
+    fn now(&self) -> u64 {
+        unsafe { _rdtsc() }
+    }
+
+    /// without a TSC we count nanoseconds since the first call
+    #[cfg(not(target_arch = "x86_64"))]
+    #[inline]
+    fn now(&self) -> u64 {
+        use std::sync::Once;
+        use std::time::Instant;
+        static INIT: Once = Once::new();
+        static mut ORIGIN: Option<Instant> = None;
+        let origin = unsafe {
+            INIT.call_once(|| ORIGIN = Some(Instant::now()));
+            ORIGIN.unwrap()
+        };
+        let elapsed = origin.elapsed();
+        // never return 0, as 0 is used for "not yet started" in several places
+        elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64 + 1
+    }
+}
+
+/// a clock which only moves when it is told to, clones share the same time,
+/// e.g. for deterministic tests of timing logic
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock {
+    now: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new(start: u64) -> ManualClock {
+        ManualClock {
+            now: Arc::new(AtomicU64::new(start)),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+
+    /// advances the clock and returns the new time
+    pub fn advance(&self, cycles: u64) -> u64 {
+        self.now.fetch_add(cycles, Ordering::SeqCst) + cycles
+    }
+}
+
+impl Clock for ManualClock {
+    #[inline]
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
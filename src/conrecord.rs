@@ -1,23 +1,28 @@
-use std::arch::x86_64::_rdtsc;
+use std::cmp;
+use std::convert::TryFrom;
 use std::fmt;
 use std::net::{Ipv4Addr, SocketAddrV4};
 
 use separator::Separatable;
-use recstore::Storable;
+use clock::{Clock, TscClock};
+use recstore::{BinaryRecord, RecordReader, Storable, DUMP_VERSION};
 use {TcpRole, TcpState, ReleaseCause, tcp_start_state};
 
-#[derive(Clone, Copy, Debug)]
-//#[repr(align(64))]
+/// fits into one cache line, the traffic counters of a connection are kept in ConCounters
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(into = "ConRecordData", try_from = "ConRecordData")]
+#[repr(align(64))]
 pub struct ConRecord {
     base_stamp: u64,
     uid: u64,
     stamps: [u32; 6],
-    sent_payload_packets: u16,
-    recv_payload_packets: u16,
+    /// in cycles / TIME_STAMP_REDUCTION_FACTOR, NO_RTT if not measured
+    handshake_rtt: u32,
     client_ip: u32,
     client_port: u16,
     port: u16,
     state: [u8; 7],
+    /// number of state transitions, saturates at 255, only the first and the latest six are kept in state
     state_count: u8,
     server_index: u8,
     release_cause: u8,
@@ -25,18 +30,170 @@ pub struct ConRecord {
     server_state: u8,
 }
 
+/// byte, packet and retransmission counters and the RTT estimation of a connection, a Store64 keeps them next to
+/// the ConRecord so that the state tracking touches only the cache line of the ConRecord
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+#[repr(align(64))]
+pub struct ConCounters {
+    sent_payload_bytes: u64,
+    recv_payload_bytes: u64,
+    sent_payload_packets: u32,
+    recv_payload_packets: u32,
+    /// all packets including pure ACKs
+    sent_packets: u32,
+    recv_packets: u32,
+    sent_retransmissions: u32,
+    recv_retransmissions: u32,
+    /// smoothed RTT and RTT variance per RFC 6298 in cycles / TIME_STAMP_REDUCTION_FACTOR,
+    /// valid if rtt_samples > 0
+    srtt: u32,
+    rttvar: u32,
+    rtt_samples: u32,
+}
+
 // we map cycle differences from u64 to u32 to minimize record size in the cache (performance)
 pub const TIME_STAMP_REDUCTION_FACTOR: u64 = 1000;
 
+const NO_RTT: u32 = u32::MAX;
+
+/// maps cycles to the reduced u32 representation, saturates below NO_RTT
+#[inline]
+fn reduce_cycles(cycles: u64) -> u32 {
+    cmp::min(cycles / TIME_STAMP_REDUCTION_FACTOR, NO_RTT as u64 - 1) as u32
+}
+
+/// the serialized form of a ConRecord, the fields are serialized in this order:
+/// - uid: u64
+/// - role: TcpRole by name, e.g. "Client"
+/// - client: socket of the client, e.g. "10.0.0.1:49152", omitted if unknown
+/// - port: u16
+/// - server_index: u8
+/// - server_state: TcpState by name
+/// - states: list of TcpState by name, starting with the start state of the role
+/// - dropped_states: u8, number of transitions which were dropped after the first transition, usually omitted
+/// - base_stamp: u64, time stamp in cycles of the first state transition
+/// - deltas: list of u32, for each further state transition the cycles since base_stamp, divided by
+///   TIME_STAMP_REDUCTION_FACTOR
+/// - handshake_rtt: u32, cycles divided by TIME_STAMP_REDUCTION_FACTOR, omitted if not measured
+/// - release_cause: ReleaseCause by name
+#[derive(Serialize, Deserialize)]
+struct ConRecordData {
+    uid: u64,
+    role: TcpRole,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client: Option<SocketAddrV4>,
+    port: u16,
+    server_index: u8,
+    server_state: TcpState,
+    states: Vec<TcpState>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    dropped_states: u8,
+    base_stamp: u64,
+    deltas: Vec<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    handshake_rtt: Option<u32>,
+    release_cause: ReleaseCause,
+}
+
+fn is_zero(n: &u8) -> bool {
+    *n == 0
+}
+
+impl From<ConRecord> for ConRecordData {
+    fn from(c: ConRecord) -> ConRecordData {
+        ConRecordData {
+            uid: c.uid,
+            role: c.role(),
+            client: if c.client_ip != 0 {
+                Some(SocketAddrV4::new(Ipv4Addr::from(c.client_ip), c.client_port))
+            } else {
+                None
+            },
+            port: c.port,
+            server_index: c.server_index,
+            server_state: c.server_state(),
+            states: c.states(),
+            dropped_states: c.dropped_states() as u8,
+            base_stamp: c.base_stamp,
+            deltas: c.deltas_to_base_stamp(),
+            handshake_rtt: if c.handshake_rtt != NO_RTT {
+                Some(c.handshake_rtt)
+            } else {
+                None
+            },
+            release_cause: c.release_cause(),
+        }
+    }
+}
+
+impl TryFrom<ConRecordData> for ConRecord {
+    type Error = String;
+
+    fn try_from(d: ConRecordData) -> Result<ConRecord, String> {
+        let mut c = ConRecord::new();
+        if d.states.is_empty() || d.states.len() > c.state.len() + 1 {
+            return Err(format!("invalid number of states: {}", d.states.len()));
+        }
+        if d.dropped_states > 0 && (d.states.len() != c.state.len() + 1 || d.dropped_states > 255 - 7) {
+            return Err(format!(
+                "{} dropped states with {} states",
+                d.dropped_states,
+                d.states.len()
+            ));
+        }
+        if d.deltas.len() != cmp::max(d.states.len(), 2) - 2 {
+            return Err(format!(
+                "{} deltas do not match {} states",
+                d.deltas.len(),
+                d.states.len()
+            ));
+        }
+        c.uid = d.uid;
+        c.role = d.role as u8;
+        if let Some(client) = d.client {
+            c.client_ip = u32::from(*client.ip());
+            c.client_port = client.port();
+        }
+        c.port = d.port;
+        c.server_index = d.server_index;
+        c.server_state = d.server_state as u8;
+        c.state_count = d.states.len() as u8 - 1 + d.dropped_states;
+        for (i, state) in d.states[1..].iter().enumerate() {
+            c.state[i] = *state as u8;
+        }
+        c.base_stamp = d.base_stamp;
+        c.stamps[..d.deltas.len()].copy_from_slice(&d.deltas);
+        c.handshake_rtt = d.handshake_rtt.map(|rtt| cmp::min(rtt, NO_RTT - 1)).unwrap_or(NO_RTT);
+        c.release_cause = d.release_cause as u8;
+        Ok(c)
+    }
+}
+
 pub trait HasTcpState {
-    fn push_state(&mut self, state: TcpState);
+    #[inline]
+    fn push_state(&mut self, state: TcpState) {
+        self.push_state_at(state, TscClock.now())
+    }
+    /// records the transition into state at time now in cycles, e.g. from a ManualClock
+    fn push_state_at(&mut self, state: TcpState, now: u64);
     fn last_state(&self) -> TcpState;
+    /// the start state followed by the recorded states, transitions after the first are dropped
+    /// when the history is full, see dropped_states
     fn states(&self) -> Vec<TcpState>;
+    /// number of transitions missing between the first and second recorded state in states()
+    /// and deltas_to_base_stamp()
+    fn dropped_states(&self) -> usize;
     fn get_last_stamp(&self) -> Option<u64>;
     fn get_first_stamp(&self) -> Option<u64>;
+    /// for each recorded state after the first the time since base stamp, divided by TIME_STAMP_REDUCTION_FACTOR
     fn deltas_to_base_stamp(&self) -> Vec<u32>;
     fn release_cause(&self) -> ReleaseCause;
     fn set_release_cause(&mut self, cause: ReleaseCause);
+    /// RTT of the handshake in cycles, i.e. from SYN to SYN-ACK for clients and from SYN-ACK to ACK for servers,
+    /// set by push_state on the transition into Established unless set before
+    fn handshake_rtt(&self) -> Option<u64>;
+    fn set_handshake_rtt(&mut self, rtt: u64);
 }
 
 pub trait HasConData {
@@ -48,10 +205,6 @@ pub trait HasConData {
     fn set_uid(&mut self, new_uid: u64);
     fn server_index(&self) -> u8;
     fn set_server_index(&mut self, index: u8);
-    fn sent_payload_packets(&self) -> u16;
-    fn recv_payload_packets(&self) -> u16;
-    fn inc_sent_payload_pkts(&mut self) -> u16;
-    fn inc_recv_payload_pkts(&mut self) -> u16;
     fn server_state(&self) -> TcpState;
     fn set_server_state(&mut self, state: TcpState);
 }
@@ -59,11 +212,16 @@ pub trait HasConData {
 impl ConRecord {
     #[inline]
     pub fn init(&mut self, role: TcpRole, port: u16, sock: Option<(u32, u16)>) {
+        self.init_at(role, port, sock, TscClock.now())
+    }
+
+    /// like init, now is the time stamp in cycles used as uid, e.g. from a ManualClock
+    #[inline]
+    pub fn init_at(&mut self, role: TcpRole, port: u16, sock: Option<(u32, u16)>, now: u64) {
         self.state_count = 0;
         self.base_stamp = 0;
-        self.sent_payload_packets = 0;
-        self.recv_payload_packets = 0;
-        self.uid = unsafe { _rdtsc() };
+        self.handshake_rtt = NO_RTT;
+        self.uid = now;
         self.server_index = 0;
         let s = sock.unwrap_or((0, 0));
         self.client_ip = s.0;
@@ -82,6 +240,12 @@ impl ConRecord {
     pub fn base_stamp(&self) -> u64 {
         self.base_stamp
     }
+
+    /// number of states in self.state
+    #[inline]
+    fn recorded_states(&self) -> usize {
+        cmp::min(self.state_count as usize, self.state.len())
+    }
 }
 
 impl HasConData for ConRecord {
@@ -126,28 +290,6 @@ impl HasConData for ConRecord {
         self.server_index = index
     }
 
-    #[inline]
-    fn sent_payload_packets(&self) -> u16 {
-        self.sent_payload_packets
-    }
-
-    #[inline]
-    fn recv_payload_packets(&self) -> u16 {
-        self.recv_payload_packets
-    }
-
-    #[inline]
-    fn inc_sent_payload_pkts(&mut self) -> u16 {
-        self.sent_payload_packets += 1;
-        self.sent_payload_packets
-    }
-
-    #[inline]
-    fn inc_recv_payload_pkts(&mut self) -> u16 {
-        self.recv_payload_packets += 1;
-        self.recv_payload_packets
-    }
-
     #[inline]
     fn server_state(&self) -> TcpState {
         TcpState::from(self.server_state)
@@ -161,15 +303,33 @@ impl HasConData for ConRecord {
 
 impl HasTcpState for ConRecord {
     #[inline]
-    fn push_state(&mut self, state: TcpState) {
-        self.state[self.state_count as usize] = state as u8;
+    fn push_state_at(&mut self, state: TcpState, now: u64) {
         if self.state_count == 0 {
-            self.base_stamp = unsafe { _rdtsc() };
+            self.state[0] = state as u8;
+            self.base_stamp = now;
         } else {
-            self.stamps[self.state_count as usize - 1] =
-                ((unsafe { _rdtsc() } - self.base_stamp) / TIME_STAMP_REDUCTION_FACTOR) as u32;
+            let delta = (now.saturating_sub(self.base_stamp) / TIME_STAMP_REDUCTION_FACTOR) as u32;
+            let n = self.recorded_states();
+            let last_state = self.last_state();
+            if state == TcpState::Established
+                && (last_state == TcpState::SynSent || last_state == TcpState::SynReceived)
+                && self.handshake_rtt == NO_RTT
+            {
+                let last_delta = if n > 1 { self.stamps[n - 2] } else { 0 };
+                self.handshake_rtt = cmp::min(delta.saturating_sub(last_delta), NO_RTT - 1);
+            }
+            if n < self.state.len() {
+                self.state[n] = state as u8;
+                self.stamps[n - 1] = delta;
+            } else {
+                // keep the first transition, drop the oldest of the others
+                self.state.copy_within(2.., 1);
+                self.stamps.copy_within(1.., 0);
+                self.state[n - 1] = state as u8;
+                self.stamps[n - 2] = delta;
+            }
         }
-        self.state_count += 1;
+        self.state_count = self.state_count.saturating_add(1);
     }
 
     #[inline]
@@ -177,25 +337,30 @@ impl HasTcpState for ConRecord {
         if self.state_count == 0 {
             tcp_start_state(self.role())
         } else {
-            TcpState::from(self.state[self.state_count as usize - 1])
+            TcpState::from(self.state[self.recorded_states() - 1])
         }
     }
 
     #[inline]
     fn states(&self) -> Vec<TcpState> {
-        let mut result = vec![tcp_start_state(self.role()); self.state_count as usize + 1];
-        for i in 0..self.state_count as usize {
+        let mut result = vec![tcp_start_state(self.role()); self.recorded_states() + 1];
+        for i in 0..self.recorded_states() {
             result[i + 1] = TcpState::from(self.state[i]);
         }
         result
     }
 
+    #[inline]
+    fn dropped_states(&self) -> usize {
+        self.state_count as usize - self.recorded_states()
+    }
+
     #[inline]
     fn get_last_stamp(&self) -> Option<u64> {
-        match self.state_count {
+        match self.recorded_states() {
             0 => None,
             1 => Some(self.base_stamp),
-            _ => Some(self.base_stamp + self.stamps[self.state_count as usize - 2] as u64 * TIME_STAMP_REDUCTION_FACTOR),
+            n => Some(self.base_stamp + self.stamps[n - 2] as u64 * TIME_STAMP_REDUCTION_FACTOR),
         }
     }
 
@@ -210,7 +375,7 @@ impl HasTcpState for ConRecord {
 
     fn deltas_to_base_stamp(&self) -> Vec<u32> {
         if self.state_count >= 2 {
-            self.stamps[0..(self.state_count as usize - 1)].iter().map(|s| *s).collect()
+            self.stamps[0..(self.recorded_states() - 1)].iter().map(|s| *s).collect()
         } else {
             vec![]
         }
@@ -225,13 +390,31 @@ impl HasTcpState for ConRecord {
     fn set_release_cause(&mut self, cause: ReleaseCause) {
         self.release_cause = cause as u8;
     }
+
+    #[inline]
+    fn handshake_rtt(&self) -> Option<u64> {
+        if self.handshake_rtt != NO_RTT {
+            Some(self.handshake_rtt as u64 * TIME_STAMP_REDUCTION_FACTOR)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    fn set_handshake_rtt(&mut self, rtt: u64) {
+        self.handshake_rtt = reduce_cycles(rtt);
+    }
+}
+
+fn rtt_string(rtt: Option<u64>) -> String {
+    rtt.map(|rtt| rtt.separated_string()).unwrap_or_else(|| "-".to_string())
 }
 
 impl fmt::Display for ConRecord {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "({:?}, {:21}, {:6}, {:3}, {:7}, {:7}, {:?}, {:?}, {}, {:?})",
+            "({:?}, {:21}, {:6}, {:3}, {:?}{}, {:?}, {}, {:?}, rtt= {})",
             self.role(),
             if self.client_ip != 0 {
                 SocketAddrV4::new(Ipv4Addr::from(self.client_ip), self.client_port).to_string()
@@ -240,15 +423,20 @@ impl fmt::Display for ConRecord {
             },
             self.port(),
             self.server_index,
-            self.sent_payload_packets,
-            self.recv_payload_packets,
             self.states(),
+            if self.dropped_states() > 0 {
+                format!(" ({} dropped)", self.dropped_states())
+            } else {
+                String::new()
+            },
             self.release_cause(),
             self.base_stamp.separated_string(),
             self.deltas_to_base_stamp()
                 .iter()
                 .map(|u| u.separated_string())
                 .collect::<Vec<_>>(),
+            // handshake in cycles
+            rtt_string(self.handshake_rtt()),
         )
     }
 }
@@ -264,13 +452,452 @@ impl Storable for ConRecord {
             base_stamp: 0,
             state: [TcpState::Closed as u8; 7],
             stamps: [0u32; 6],
+            handshake_rtt: NO_RTT,
             port: 0u16,
             client_ip: 0,
             client_port: 0,
-            sent_payload_packets: 0,
-            recv_payload_packets: 0,
             uid: 0,
             server_state: TcpState::Listen as u8,
         }
     }
 }
+
+/// layout of the 64 bytes: base_stamp u64, uid u64, stamps 6 x u32, handshake_rtt u32 (u32::MAX if not measured),
+/// client_ip u32, client_port u16, port u16, state 7 x u8, state_count u8, server_index u8, release_cause u8,
+/// role u8, server_state u8
+///
+/// dumps of version 2 lack the handshake_rtt (60 bytes), dumps of version 1 store the payload packet counters as
+/// two u16 in place of the handshake_rtt (64 bytes), they are skipped
+impl BinaryRecord for ConRecord {
+    const RECORD_TYPE: u16 = 1;
+    const RECORD_SIZE: usize = 64;
+
+    fn record_size(version: u16) -> Option<usize> {
+        match version {
+            1 => Some(64),
+            2 => Some(60),
+            DUMP_VERSION => Some(ConRecord::RECORD_SIZE),
+            _ => None,
+        }
+    }
+
+    fn write_record(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.base_stamp.to_le_bytes());
+        buf.extend_from_slice(&self.uid.to_le_bytes());
+        for stamp in &self.stamps {
+            buf.extend_from_slice(&stamp.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.handshake_rtt.to_le_bytes());
+        buf.extend_from_slice(&self.client_ip.to_le_bytes());
+        buf.extend_from_slice(&self.client_port.to_le_bytes());
+        buf.extend_from_slice(&self.port.to_le_bytes());
+        buf.extend_from_slice(&self.state);
+        buf.extend_from_slice(&[
+            self.state_count,
+            self.server_index,
+            self.release_cause,
+            self.role,
+            self.server_state,
+        ]);
+    }
+
+    fn read_record(reader: &mut RecordReader, version: u16) -> Result<ConRecord, String> {
+        // fields missing in older versions keep the values of a new record
+        let mut c = ConRecord::new();
+        c.base_stamp = reader.u64();
+        c.uid = reader.u64();
+        for stamp in c.stamps.iter_mut() {
+            *stamp = reader.u32();
+        }
+        match version {
+            // the payload packet counters moved to ConCounters
+            1 => {
+                reader.u32();
+            }
+            2 => (),
+            _ => c.handshake_rtt = reader.u32(),
+        }
+        c.client_ip = reader.u32();
+        c.client_port = reader.u16();
+        c.port = reader.u16();
+        for state in c.state.iter_mut() {
+            *state = reader.u8();
+        }
+        c.state_count = reader.u8();
+        if let Some(state) = c.state[..c.recorded_states()]
+            .iter()
+            .find(|s| **s > TcpState::Closed as u8)
+        {
+            return Err(format!("invalid state {}", state));
+        }
+        c.server_index = reader.u8();
+        c.release_cause = reader.u8();
+        if c.release_cause >= ReleaseCause::MaxCauses as u8 {
+            return Err(format!("invalid release cause {}", c.release_cause));
+        }
+        c.role = reader.u8();
+        if c.role > TcpRole::Server as u8 {
+            return Err(format!("invalid role {}", c.role));
+        }
+        c.server_state = reader.u8();
+        if c.server_state > TcpState::Closed as u8 {
+            return Err(format!("invalid server state {}", c.server_state));
+        }
+        Ok(c)
+    }
+}
+
+impl ConCounters {
+    #[inline]
+    pub fn sent_payload_packets(&self) -> u32 {
+        self.sent_payload_packets
+    }
+
+    #[inline]
+    pub fn recv_payload_packets(&self) -> u32 {
+        self.recv_payload_packets
+    }
+
+    #[inline]
+    pub fn inc_sent_payload_pkts(&mut self) -> u32 {
+        self.sent_payload_packets = self.sent_payload_packets.saturating_add(1);
+        self.sent_payload_packets
+    }
+
+    #[inline]
+    pub fn inc_recv_payload_pkts(&mut self) -> u32 {
+        self.recv_payload_packets = self.recv_payload_packets.saturating_add(1);
+        self.recv_payload_packets
+    }
+
+    /// bytes of TCP payload
+    #[inline]
+    pub fn sent_payload_bytes(&self) -> u64 {
+        self.sent_payload_bytes
+    }
+
+    #[inline]
+    pub fn recv_payload_bytes(&self) -> u64 {
+        self.recv_payload_bytes
+    }
+
+    /// all packets including pure ACKs
+    #[inline]
+    pub fn sent_packets(&self) -> u32 {
+        self.sent_packets
+    }
+
+    #[inline]
+    pub fn recv_packets(&self) -> u32 {
+        self.recv_packets
+    }
+
+    /// counts a packet with payload_size bytes of TCP payload, and a payload packet if payload_size > 0
+    #[inline]
+    pub fn count_sent_packet(&mut self, payload_size: usize) {
+        self.sent_packets = self.sent_packets.saturating_add(1);
+        if payload_size > 0 {
+            self.sent_payload_packets = self.sent_payload_packets.saturating_add(1);
+            self.sent_payload_bytes += payload_size as u64;
+        }
+    }
+
+    #[inline]
+    pub fn count_recv_packet(&mut self, payload_size: usize) {
+        self.recv_packets = self.recv_packets.saturating_add(1);
+        if payload_size > 0 {
+            self.recv_payload_packets = self.recv_payload_packets.saturating_add(1);
+            self.recv_payload_bytes += payload_size as u64;
+        }
+    }
+
+    #[inline]
+    pub fn sent_retransmissions(&self) -> u32 {
+        self.sent_retransmissions
+    }
+
+    #[inline]
+    pub fn recv_retransmissions(&self) -> u32 {
+        self.recv_retransmissions
+    }
+
+    #[inline]
+    pub fn inc_sent_retransmissions(&mut self) -> u32 {
+        self.sent_retransmissions = self.sent_retransmissions.saturating_add(1);
+        self.sent_retransmissions
+    }
+
+    #[inline]
+    pub fn inc_recv_retransmissions(&mut self) -> u32 {
+        self.recv_retransmissions = self.recv_retransmissions.saturating_add(1);
+        self.recv_retransmissions
+    }
+
+    /// updates smoothed RTT and RTT variance with an RTT measured from an ACK, in cycles (RFC 6298)
+    #[inline]
+    pub fn add_rtt_sample(&mut self, rtt: u64) {
+        let r = reduce_cycles(rtt) as u64;
+        if self.rtt_samples == 0 {
+            self.srtt = r as u32;
+            self.rttvar = (r / 2) as u32;
+        } else {
+            let srtt = self.srtt as u64;
+            let deviation = if srtt > r { srtt - r } else { r - srtt };
+            // alpha = 1/8, beta = 1/4
+            self.rttvar = ((3 * self.rttvar as u64 + deviation) / 4) as u32;
+            self.srtt = ((7 * srtt + r) / 8) as u32;
+        }
+        self.rtt_samples = self.rtt_samples.saturating_add(1);
+    }
+
+    /// smoothed RTT in cycles
+    #[inline]
+    pub fn srtt(&self) -> Option<u64> {
+        if self.rtt_samples > 0 {
+            Some(self.srtt as u64 * TIME_STAMP_REDUCTION_FACTOR)
+        } else {
+            None
+        }
+    }
+
+    /// RTT variance in cycles
+    #[inline]
+    pub fn rttvar(&self) -> Option<u64> {
+        if self.rtt_samples > 0 {
+            Some(self.rttvar as u64 * TIME_STAMP_REDUCTION_FACTOR)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn rtt_samples(&self) -> u32 {
+        self.rtt_samples
+    }
+}
+
+impl fmt::Display for ConCounters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "({:7}, {:7}, {:>11}, {:>11}, {:7}, {:7}, {:5}, {:5}, rtt= {}/{})",
+            self.sent_payload_packets,
+            self.recv_payload_packets,
+            self.sent_payload_bytes.separated_string(),
+            self.recv_payload_bytes.separated_string(),
+            self.sent_packets,
+            self.recv_packets,
+            self.sent_retransmissions,
+            self.recv_retransmissions,
+            // smoothed and variance in cycles
+            rtt_string(self.srtt()),
+            rtt_string(self.rttvar()),
+        )
+    }
+}
+
+impl Storable for ConCounters {
+    #[inline]
+    fn new() -> ConCounters {
+        ConCounters::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+    use clock::ManualClock;
+    use tcp_common::{TcpCounter, TcpStatistics};
+    use toml;
+
+    #[test]
+    fn serde_con_record() {
+        let mut c = ConRecord::new();
+        c.init(TcpRole::Client, 49152, Some((0x0a00_0001, 80)));
+        c.push_state(TcpState::SynSent);
+        c.push_state(TcpState::Established);
+        c.set_release_cause(ReleaseCause::PassiveClose);
+
+        let text = toml::to_string(&c).unwrap();
+        assert!(text.contains("client = \"10.0.0.1:80\""));
+        assert!(text.contains("states = [\"Closed\", \"SynSent\", \"Established\"]"));
+        assert!(text.contains("release_cause = \"PassiveClose\""));
+        let d: ConRecord = toml::from_str(&text).unwrap();
+        assert_eq!(d.uid(), c.uid());
+        assert_eq!(d.sock(), c.sock());
+        assert_eq!(d.port(), 49152);
+        assert_eq!(d.states(), c.states());
+        assert_eq!(d.base_stamp(), c.base_stamp());
+        assert_eq!(d.deltas_to_base_stamp(), c.deltas_to_base_stamp());
+        assert_eq!(d.handshake_rtt(), c.handshake_rtt());
+        assert_eq!(d.release_cause(), ReleaseCause::PassiveClose);
+        assert_eq!(d.server_state(), TcpState::Closed);
+
+        // a server record without client socket and state transitions
+        let mut s = ConRecord::new();
+        s.init(TcpRole::Server, 80, None);
+        let text = toml::to_string(&s).unwrap();
+        assert!(!text.contains("client"));
+        let d: ConRecord = toml::from_str(&text).unwrap();
+        assert_eq!(d.role(), TcpRole::Server);
+        assert_eq!(d.states(), vec![TcpState::Listen]);
+
+        // deltas must match the states
+        let text = text.replace("deltas = []", "deltas = [1]");
+        assert!(toml::from_str::<ConRecord>(&text).is_err());
+    }
+
+    #[test]
+    fn serde_con_counters() {
+        let mut counters = ConCounters::new();
+        counters.count_sent_packet(100);
+        counters.count_recv_packet(0);
+        counters.inc_recv_retransmissions();
+        let text = toml::to_string(&counters).unwrap();
+        assert!(text.contains("sent_payload_bytes = 100"));
+        let d: ConCounters = toml::from_str(&text).unwrap();
+        assert_eq!(d.sent_payload_packets(), 1);
+        assert_eq!(d.sent_payload_bytes(), 100);
+        assert_eq!(d.sent_packets(), 1);
+        assert_eq!(d.recv_packets(), 1);
+        assert_eq!(d.recv_payload_packets(), 0);
+        assert_eq!(d.recv_retransmissions(), 1);
+        // missing counters are 0
+        let d: ConCounters = toml::from_str("recv_packets = 3").unwrap();
+        assert_eq!(d.recv_packets(), 3);
+        assert_eq!(d.sent_packets(), 0);
+    }
+
+    #[test]
+    fn state_history_overflow() {
+        // one cache line each
+        assert_eq!(mem::size_of::<ConRecord>(), 64);
+        assert_eq!(mem::align_of::<ConRecord>(), 64);
+        assert_eq!(mem::size_of::<ConCounters>(), 64);
+        let mut c = ConRecord::new();
+        c.init(TcpRole::Client, 49152, None);
+        let transitions = [
+            TcpState::SynSent,
+            TcpState::Established,
+            TcpState::FinWait1,
+            TcpState::Closing,
+            TcpState::FinWait1,
+            TcpState::Closing,
+            TcpState::FinWait1,
+            TcpState::FinWait2,
+            TcpState::Closed,
+            TcpState::Closed,
+        ];
+        for (i, state) in transitions.iter().enumerate() {
+            c.push_state(*state);
+            assert_eq!(c.last_state(), *state);
+            assert_eq!(c.dropped_states(), i.saturating_sub(6));
+            assert_eq!(c.states().len(), cmp::min(i + 2, 8));
+            assert_eq!(c.deltas_to_base_stamp().len(), cmp::min(i, 6));
+        }
+        let mut expected = vec![TcpState::Closed, TcpState::SynSent];
+        expected.extend_from_slice(&transitions[4..]);
+        assert_eq!(c.states(), expected);
+        let deltas = c.deltas_to_base_stamp();
+        assert!(deltas.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(
+            c.get_last_stamp().unwrap(),
+            c.base_stamp() + deltas[5] as u64 * TIME_STAMP_REDUCTION_FACTOR
+        );
+        assert!(c.to_string().contains("(3 dropped)"));
+
+        let text = toml::to_string(&c).unwrap();
+        assert!(text.contains("dropped_states = 3"));
+        let d: ConRecord = toml::from_str(&text).unwrap();
+        assert_eq!(d.states(), c.states());
+        assert_eq!(d.dropped_states(), 3);
+        assert_eq!(d.deltas_to_base_stamp(), deltas);
+
+        // the counter saturates
+        for _ in 0..300 {
+            c.push_state(TcpState::Closed);
+        }
+        assert_eq!(c.dropped_states(), 255 - 7);
+        assert_eq!(c.states().len(), 8);
+    }
+
+    #[test]
+    fn rtt_estimation() {
+        let mut c = ConRecord::new();
+        c.init(TcpRole::Client, 49152, None);
+        assert_eq!(c.handshake_rtt(), None);
+        assert!(c.to_string().ends_with("rtt= -)"));
+
+        c.push_state(TcpState::SynSent);
+        c.push_state(TcpState::Established);
+        let handshake_rtt = c.handshake_rtt().unwrap();
+        assert_eq!(
+            handshake_rtt,
+            c.deltas_to_base_stamp()[0] as u64 * TIME_STAMP_REDUCTION_FACTOR
+        );
+        c.set_handshake_rtt(2_000_000);
+        assert_eq!(c.handshake_rtt(), Some(2_000_000));
+        assert!(c.to_string().ends_with("rtt= 2,000,000)"));
+        let d: ConRecord = toml::from_str(&toml::to_string(&c).unwrap()).unwrap();
+        assert_eq!(d.handshake_rtt(), Some(2_000_000));
+
+        // RFC 6298: the first sample initializes, the following are smoothed
+        let mut counters = ConCounters::new();
+        assert_eq!(counters.srtt(), None);
+        assert!(counters.to_string().ends_with("rtt= -/-)"));
+        counters.add_rtt_sample(1_000_000);
+        assert_eq!(counters.srtt(), Some(1_000_000));
+        assert_eq!(counters.rttvar(), Some(500_000));
+        counters.add_rtt_sample(2_600_000);
+        assert_eq!(counters.srtt(), Some(1_200_000));
+        assert_eq!(counters.rttvar(), Some(775_000));
+        assert_eq!(counters.rtt_samples(), 2);
+        assert!(counters.to_string().ends_with("rtt= 1,200,000/775,000)"));
+
+        let d: ConCounters = toml::from_str(&toml::to_string(&counters).unwrap()).unwrap();
+        assert_eq!(d.srtt(), Some(1_200_000));
+        assert_eq!(d.rttvar(), Some(775_000));
+        assert_eq!(d.rtt_samples(), 2);
+
+        // a server measures from SYN-ACK to ACK
+        let mut s = ConRecord::new();
+        s.init(TcpRole::Server, 80, None);
+        s.push_state(TcpState::SynReceived);
+        assert_eq!(s.handshake_rtt(), None);
+        s.push_state(TcpState::Established);
+        assert!(s.handshake_rtt().is_some());
+    }
+
+    #[test]
+    fn timing_with_manual_clock() {
+        let clock = ManualClock::new(1_000_000);
+        let mut c = ConRecord::new();
+        c.init_at(TcpRole::Client, 49152, None, clock.now());
+        assert_eq!(c.uid(), 1_000_000);
+        c.push_state_at(TcpState::SynSent, clock.now());
+        c.push_state_at(TcpState::Established, clock.advance(50 * TIME_STAMP_REDUCTION_FACTOR));
+        c.push_state_at(TcpState::FinWait1, clock.advance(100 * TIME_STAMP_REDUCTION_FACTOR));
+        assert_eq!(c.get_first_stamp(), Some(1_000_000));
+        assert_eq!(c.deltas_to_base_stamp(), vec![50, 150]);
+        assert_eq!(c.get_last_stamp(), Some(clock.now()));
+        assert_eq!(c.handshake_rtt(), Some(50 * TIME_STAMP_REDUCTION_FACTOR));
+    }
+
+    #[test]
+    fn serde_tcp_counter() {
+        let mut counter = TcpCounter::new();
+        counter[TcpStatistics::SentSyn] = 10;
+        counter[TcpStatistics::RecvRst] = 2;
+        let text = toml::to_string(&counter).unwrap();
+        assert!(text.contains("SentSyn = 10"));
+        let d: TcpCounter = toml::from_str(&text).unwrap();
+        assert_eq!(d[TcpStatistics::SentSyn], 10);
+        assert_eq!(d[TcpStatistics::RecvRst], 2);
+        let d: TcpCounter = toml::from_str("RecvAck = 3").unwrap();
+        assert_eq!(d[TcpStatistics::RecvAck], 3);
+        assert_eq!(d[TcpStatistics::SentSyn], 0);
+        assert!(toml::from_str::<TcpCounter>("Count = 3").is_err());
+        assert!(toml::from_str::<TcpCounter>("Unknown = 3").is_err());
+    }
+}
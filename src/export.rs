@@ -0,0 +1,378 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use serde_json;
+use conrecord::{ConCounters, ConRecord, HasConData, HasTcpState, TIME_STAMP_REDUCTION_FACTOR};
+use system::SystemData;
+use {ReleaseCause, TcpRole, TcpState};
+
+/// output formats for connection records
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// comma separated values with a header line, lists are separated by blanks
+    Csv,
+    /// one JSON object per line
+    JsonLines,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ExportFormat, String> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "jsonl" | "json-lines" | "jsonlines" => Ok(ExportFormat::JsonLines),
+            _ => Err(format!("unknown export format {}, expected csv or jsonl", s)),
+        }
+    }
+}
+
+const CSV_COLUMNS: [&str; 23] = [
+    "uid",
+    "role",
+    "client_ip",
+    "client_port",
+    "port",
+    "server_index",
+    "server_state",
+    "states",
+    "dropped_states",
+    "stamps_us",
+    "sent_payload_packets",
+    "recv_payload_packets",
+    "sent_payload_bytes",
+    "recv_payload_bytes",
+    "sent_packets",
+    "recv_packets",
+    "sent_retransmissions",
+    "recv_retransmissions",
+    "handshake_rtt_us",
+    "srtt_us",
+    "rttvar_us",
+    "rtt_samples",
+    "release_cause",
+];
+
+/// one exported connection
+#[derive(Clone, Debug, Serialize)]
+pub struct ConRecordRow {
+    pub uid: u64,
+    pub role: TcpRole,
+    pub client_ip: Option<Ipv4Addr>,
+    pub client_port: Option<u16>,
+    pub port: u16,
+    pub server_index: u8,
+    pub server_state: TcpState,
+    /// starts with the start state of the role
+    pub states: Vec<TcpState>,
+    /// transitions which are missing after the first transition in states and stamps_us
+    pub dropped_states: usize,
+    /// time stamp in µs of each state transition, i.e. one for each state after the start state
+    pub stamps_us: Vec<u64>,
+    /// the counters are empty or null for stores without ConCounters
+    pub sent_payload_packets: Option<u32>,
+    pub recv_payload_packets: Option<u32>,
+    pub sent_payload_bytes: Option<u64>,
+    pub recv_payload_bytes: Option<u64>,
+    /// all packets including pure ACKs
+    pub sent_packets: Option<u32>,
+    pub recv_packets: Option<u32>,
+    pub sent_retransmissions: Option<u32>,
+    pub recv_retransmissions: Option<u32>,
+    /// RTT values in µs, empty or null if not measured
+    pub handshake_rtt_us: Option<u64>,
+    pub srtt_us: Option<u64>,
+    pub rttvar_us: Option<u64>,
+    pub rtt_samples: Option<u32>,
+    pub release_cause: ReleaseCause,
+    /// Display of the second record in a Store64
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+}
+
+impl ConRecordRow {
+    pub fn new(c: &ConRecord, system_data: &SystemData) -> ConRecordRow {
+        let (client_ip, client_port) = c.sock();
+        let to_us = |cycles: u64| system_data.cycles_to_duration(cycles).as_micros() as u64;
+        let mut stamps_us = Vec::with_capacity(8);
+        if let Some(base_stamp) = c.get_first_stamp() {
+            stamps_us.push(to_us(base_stamp));
+            stamps_us.extend(
+                c.deltas_to_base_stamp()
+                    .iter()
+                    .map(|d| to_us(base_stamp + *d as u64 * TIME_STAMP_REDUCTION_FACTOR)),
+            );
+        }
+        ConRecordRow {
+            uid: c.uid(),
+            role: c.role(),
+            client_ip: if client_ip != 0 {
+                Some(Ipv4Addr::from(client_ip))
+            } else {
+                None
+            },
+            client_port: if client_ip != 0 { Some(client_port) } else { None },
+            port: c.port(),
+            server_index: c.server_index(),
+            server_state: c.server_state(),
+            states: c.states(),
+            dropped_states: c.dropped_states(),
+            stamps_us,
+            sent_payload_packets: None,
+            recv_payload_packets: None,
+            sent_payload_bytes: None,
+            recv_payload_bytes: None,
+            sent_packets: None,
+            recv_packets: None,
+            sent_retransmissions: None,
+            recv_retransmissions: None,
+            handshake_rtt_us: c.handshake_rtt().map(to_us),
+            srtt_us: None,
+            rttvar_us: None,
+            rtt_samples: None,
+            release_cause: c.release_cause(),
+            data: None,
+        }
+    }
+
+    pub fn with_counters(mut self, counters: &ConCounters, system_data: &SystemData) -> ConRecordRow {
+        let to_us = |cycles: u64| system_data.cycles_to_duration(cycles).as_micros() as u64;
+        self.sent_payload_packets = Some(counters.sent_payload_packets());
+        self.recv_payload_packets = Some(counters.recv_payload_packets());
+        self.sent_payload_bytes = Some(counters.sent_payload_bytes());
+        self.recv_payload_bytes = Some(counters.recv_payload_bytes());
+        self.sent_packets = Some(counters.sent_packets());
+        self.recv_packets = Some(counters.recv_packets());
+        self.sent_retransmissions = Some(counters.sent_retransmissions());
+        self.recv_retransmissions = Some(counters.recv_retransmissions());
+        self.srtt_us = counters.srtt().map(to_us);
+        self.rttvar_us = counters.rttvar().map(to_us);
+        self.rtt_samples = Some(counters.rtt_samples());
+        self
+    }
+
+    pub fn with_data<T: fmt::Display>(mut self, data: &T) -> ConRecordRow {
+        self.data = Some(data.to_string());
+        self
+    }
+
+    fn write_csv<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let join = |items: Vec<String>| items.join(" ");
+        write!(
+            w,
+            "{},{:?},{},{},{},{},{:?},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{:?}",
+            self.uid,
+            self.role,
+            self.client_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            self.client_port.map(|port| port.to_string()).unwrap_or_default(),
+            self.port,
+            self.server_index,
+            self.server_state,
+            join(self.states.iter().map(|s| format!("{:?}", s)).collect()),
+            self.dropped_states,
+            join(self.stamps_us.iter().map(|s| s.to_string()).collect()),
+            optional(self.sent_payload_packets),
+            optional(self.recv_payload_packets),
+            optional(self.sent_payload_bytes),
+            optional(self.recv_payload_bytes),
+            optional(self.sent_packets),
+            optional(self.recv_packets),
+            optional(self.sent_retransmissions),
+            optional(self.recv_retransmissions),
+            optional(self.handshake_rtt_us),
+            optional(self.srtt_us),
+            optional(self.rttvar_us),
+            optional(self.rtt_samples),
+            self.release_cause,
+        )?;
+        if let Some(ref data) = self.data {
+            write!(w, ",{}", csv_field(data))?;
+        }
+        writeln!(w)
+    }
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// quotes a CSV field if required (RFC 4180)
+fn csv_field(s: &str) -> Cow<str> {
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        Cow::Owned(format!("\"{}\"", s.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+/// writes the rows in the given format, with_data adds the column "data" to the CSV header,
+/// returns the number of rows written
+pub fn write_rows<W, I>(w: &mut W, format: ExportFormat, with_data: bool, rows: I) -> io::Result<usize>
+where
+    W: Write,
+    I: IntoIterator<Item = ConRecordRow>,
+{
+    if format == ExportFormat::Csv {
+        write!(w, "{}", CSV_COLUMNS.join(","))?;
+        if with_data {
+            write!(w, ",data")?;
+        }
+        writeln!(w)?;
+    }
+    let mut count = 0;
+    for row in rows {
+        match format {
+            ExportFormat::Csv => row.write_csv(w)?,
+            ExportFormat::JsonLines => {
+                serde_json::to_writer(&mut *w, &row)?;
+                writeln!(w)?;
+            }
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use recstore::{CounterStore, RecordStore, SimpleStore, Storable, Store64};
+    use serde_json::Value;
+
+    fn system_data() -> SystemData {
+        SystemData {
+            cpu_clock: 2_000_000_000,
+            topology: Default::default(),
+        }
+    }
+
+    fn fill(c: &mut ConRecord, i: u32) {
+        c.init(
+            TcpRole::Client,
+            1000 + i as u16,
+            if i > 0 { Some((0x0a00_0000 + i, 80)) } else { None },
+        );
+        c.set_uid(i as u64);
+        c.push_state(TcpState::SynSent);
+        c.push_state(TcpState::Established);
+        c.set_release_cause(ReleaseCause::ActiveClose);
+    }
+
+    #[test]
+    fn export_record_store() {
+        let mut store = RecordStore::<ConRecord>::with_capacity(4);
+        for i in 0..2 {
+            let slot = store.get_next_slot();
+            fill(store.get_mut(slot), i);
+        }
+        // time stamps in µs at 2 GHz
+        let stamps_us = |c: &ConRecord| {
+            (
+                c.base_stamp() / 2000,
+                (c.base_stamp() + c.deltas_to_base_stamp()[0] as u64 * 1000) / 2000,
+            )
+        };
+        let (first_0, second_0) = stamps_us(store.get(0));
+        let (first_1, second_1) = stamps_us(store.get(1));
+
+        let mut csv = Vec::new();
+        assert_eq!(store.export(&mut csv, ExportFormat::Csv, &system_data()).unwrap(), 2);
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], CSV_COLUMNS.join(","));
+        assert_eq!(
+            lines[1],
+            format!(
+                "0,Client,,,1000,0,Closed,Closed SynSent Established,0,{} {},,,,,,,,,{},,,,ActiveClose",
+                first_0,
+                second_0,
+                store.get(0).handshake_rtt().unwrap() / 2000
+            )
+        );
+        assert_eq!(
+            lines[2],
+            format!(
+                "1,Client,10.0.0.1,80,1001,0,Closed,Closed SynSent Established,0,{} {},,,,,,,,,{},,,,ActiveClose",
+                first_1,
+                second_1,
+                store.get(1).handshake_rtt().unwrap() / 2000
+            )
+        );
+
+        let mut json = Vec::new();
+        assert_eq!(
+            store
+                .export(&mut json, ExportFormat::JsonLines, &system_data())
+                .unwrap(),
+            2
+        );
+        let json = String::from_utf8(json).unwrap();
+        let rows: Vec<Value> = json.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["client_ip"], Value::Null);
+        assert_eq!(rows[1]["client_ip"], "10.0.0.1");
+        assert_eq!(rows[1]["client_port"], 80);
+        assert_eq!(rows[1]["states"][2], "Established");
+        assert_eq!(rows[1]["stamps_us"][1], second_1);
+        assert_eq!(rows[1]["sent_payload_packets"], Value::Null);
+        assert_eq!(
+            rows[1]["handshake_rtt_us"],
+            store.get(1).handshake_rtt().unwrap() / 2000
+        );
+        assert_eq!(rows[1]["srtt_us"], Value::Null);
+        assert_eq!(rows[1]["release_cause"], "ActiveClose");
+        assert!(rows[1].get("data").is_none());
+    }
+
+    #[derive(Clone)]
+    struct Payload(u32);
+
+    impl fmt::Display for Payload {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "payload \"{}\", more", self.0)
+        }
+    }
+
+    impl Storable for Payload {
+        fn new() -> Payload {
+            Payload(0)
+        }
+    }
+
+    #[test]
+    fn export_store64() {
+        let mut store = Store64::<Payload>::with_capacity(2);
+        let slot = store.get_next_slot();
+        fill(store.get_mut(slot), 1);
+        store.get_counters_mut(slot).count_sent_packet(100);
+        *store.get_mut_1(slot) = Payload(7);
+
+        let mut csv = Vec::new();
+        assert_eq!(store.export(&mut csv, ExportFormat::Csv, &system_data()).unwrap(), 1);
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert!(lines[0].ends_with(",release_cause,data"));
+        assert!(lines[1].contains(",1,0,100,0,1,0,0,0,"));
+        assert!(lines[1].ends_with(",,,0,ActiveClose,\"payload \"\"7\"\", more\""));
+
+        let mut json = Vec::new();
+        store
+            .export(&mut json, ExportFormat::JsonLines, &system_data())
+            .unwrap();
+        let row: Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(row["sent_payload_bytes"], 100);
+        assert_eq!(row["srtt_us"], Value::Null);
+        assert_eq!(row["data"], "payload \"7\", more");
+    }
+
+    #[test]
+    fn export_format_from_str() {
+        assert_eq!("CSV".parse::<ExportFormat>(), Ok(ExportFormat::Csv));
+        assert_eq!("jsonl".parse::<ExportFormat>(), Ok(ExportFormat::JsonLines));
+        assert!("xml".parse::<ExportFormat>().is_err());
+    }
+}
@@ -10,7 +10,10 @@ extern crate ipnet;
 extern crate separator;
 extern crate toml;
 extern crate rand;
+extern crate libc;
+extern crate serde_json;
 
+pub mod clock;
 pub mod comm;
 pub mod tasks;
 pub mod tcp_common;
@@ -20,23 +23,30 @@ pub mod io;
 pub mod utils;
 pub mod recstore;
 pub mod conrecord;
+pub mod netlink;
+pub mod export;
+pub mod analysis;
 
 pub use recstore::RecordStore;
 pub use recstore::SimpleStore;
+pub use recstore::CounterStore;
 pub use recstore::Store64;
 pub use recstore::Storable;
 pub use recstore::ConRecordOperations;
+pub use recstore::{BinaryRecord, DumpHeader};
+pub use export::ExportFormat;
 
 use comm::{MessageFrom, MessageTo, PipelineId};
-use system::SystemData;
+use system::{is_pci_address, PreflightProblem, PreflightReport, SystemData};
 use tasks::TaskType;
 use io::print_hard_statistics;
+use netlink::{LinuxIfError, LinuxIfSetup};
+use utils::tcp_port_base;
 
 use std::collections::{HashMap, HashSet};
 
 use std::net::{Ipv4Addr};
-use std::process::Command;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::mem;
 use std::env;
 use std::fs::File;
@@ -63,7 +73,7 @@ use e2d2::config::{basic_opts, read_matches, NetbricksConfiguration};
 use e2d2::scheduler::{NetBricksContext, initialize_system, SchedulerCommand, SchedulerReply, StandaloneScheduler};
 
 use tcp_common::{ReleaseCause, TcpRole, TcpState, L234Data, tcp_payload_size};
-use tcp_common::tcp_start_state;
+use tcp_common::{tcp_start_state, clamp_parsed_mss_ipv4, tcp_options, update_ipv4_packet, TcpOptionError};
 use e2d2::native::zcsi::rte_ethdev_api::{rte_log_set_global_level, rte_log_set_level, rte_log_get_global_level,
                                      rte_log_get_level};
 
@@ -92,6 +102,8 @@ pub struct RunTime<T: Sized + Clone + Send, TStore: SimpleStore + Clone> {
     /// see also get_main_channel()
     remote_receiver: Option<Receiver<MessageTo<TStore>>>,
     toml_file: String,
+    /// changes made to kernel interfaces, shared with the run_time thread which sets them up
+    linux_if_setup: Arc<Mutex<LinuxIfSetup>>,
 }
 
 impl<T: Sized + Clone + Send, TStore: 'static + SimpleStore + Clone> RunTime<T, TStore>
@@ -117,11 +129,17 @@ where
         Ok(config.clone())
     }
 
-    fn check_system(context: NetBricksContext) -> e2d2::common::Result<NetBricksContext> {
+    fn check_system(context: NetBricksContext, system_data: &SystemData) -> e2d2::common::Result<NetBricksContext> {
         let num_pmd_ports = PmdPort::num_pmd_ports();
         for i in 0..num_pmd_ports {
             PmdPort::print_eth_dev_info(i as u16);
         }
+        // pipelines run the rx and tx queue with the same index on the same core, so rx_cores suffice
+        for port in context.ports.values().filter(|p| p.is_physical()) {
+            if let Some(ref rx_cores) = port.rx_cores {
+                system_data.topology.check_numa_locality(port.name(), rx_cores);
+            }
+        }
         /*
         for port in context.ports.values() {
             if port.port_type() == &PortType::Physical {
@@ -206,21 +224,6 @@ where
             );
         }
 
-        fn am_root() -> bool {
-            match env::var("USER") {
-                Ok(val) => val == "root",
-                Err(_e) => false,
-            }
-        }
-
-        if !am_root() {
-            error!(
-                " ... must run as root, e.g.: sudo -E env \"PATH=$PATH\" $executable, see also test.sh\n\
-             Do not run 'cargo test' as root."
-            );
-            std::process::exit(1);
-        }
-
         let opts = basic_opts();
         let args: Vec<String> = vec!["-f".to_string(), toml_file.trim().to_string()];
 
@@ -230,19 +233,61 @@ where
         };
         let netbricks_configuration = read_matches(&matches, &opts);
 
+        // check the prerequisites of DPDK, instead of failing somewhere within the EAL
+        let pci_addresses: Vec<&str> = netbricks_configuration
+            .ports
+            .iter()
+            .map(|p| p.name.as_str())
+            .filter(|name| is_pci_address(name))
+            .collect();
+        // only the NUMA nodes of these cores and of the PCI devices need hugepages
+        let mut cores = netbricks_configuration.cores.clone();
+        cores.push(netbricks_configuration.primary_core);
+        for port in &netbricks_configuration.ports {
+            cores.extend(port.rx_queues.iter().chain(port.tx_queues.iter()));
+        }
+        let preflight = PreflightReport::check(&pci_addresses, &cores);
+        if !preflight.is_ok() {
+            for problem in &preflight.problems {
+                error!("preflight: {}", problem);
+            }
+            if preflight.problems.iter().any(|p| match p {
+                PreflightProblem::MissingCapability(_) => true,
+                _ => false,
+            }) {
+                error!(
+                    " ... missing privileges, e.g. run: sudo -E env \"PATH=$PATH\" $executable, see also test.sh\n\
+             Do not run 'cargo test' as root."
+                );
+            }
+            return Err(E2d2ErrorKind::RunTimeError(format!(
+                "{} preflight problem(s), first: {}",
+                preflight.problems.len(),
+                preflight.problems[0]
+            )));
+        }
+
         let config: Config<T> = RunTime::<T, TStore>::read_config(&toml_file.trim())?;
         let engine_configuration = config.engine;
 
+        let system_data = match SystemData::detect() {
+            Ok(system_data) => system_data,
+            Err(e) => {
+                error!("Error: {}", e);
+                return Err(E2d2ErrorKind::RunTimeError(e.to_string()));
+            }
+        };
+
         let (remote_sender, local_receiver) = channel::<MessageFrom<TStore>>();
         let (local_sender, remote_receiver) = channel::<MessageTo<TStore>>();
 
         match initialize_system(&netbricks_configuration)
             .map_err(|e| e.into())
-            .and_then(|ctxt| RunTime::<T, TStore>::check_system(ctxt))
+            .and_then(|ctxt| RunTime::<T, TStore>::check_system(ctxt, &system_data))
         {
             Ok(context) => Ok(RunTime {
                 run_configuration: RunConfiguration {
-                    system_data: SystemData::detect(),
+                    system_data,
                     netbricks_configuration,
                     engine_configuration,
                     flowdirector_map: HashMap::new(),
@@ -253,6 +298,7 @@ where
                 local_receiver: Some(local_receiver),
                 remote_receiver: Some(remote_receiver),
                 toml_file: toml_file.clone(),
+                linux_if_setup: Arc::new(Mutex::new(LinuxIfSetup::default())),
             }),
             Err(e) => {
                 error!("Error: {}", e);
@@ -290,6 +336,12 @@ where
         &self.toml_file
     }
 
+    /// deletes the addresses and network namespaces of the kernel interfaces, this happens also on MessageFrom::Exit.
+    /// Calling it repeatedly is harmless.
+    pub fn cleanup_kernel_interfaces(&self) -> Result<(), LinuxIfError> {
+        self.linux_if_setup.lock().unwrap().teardown()
+    }
+
     /// this returns the communication channel to the run_time thread, only the first call returns the channel
     pub fn get_main_channel(&mut self) -> Option<(Sender<MessageFrom<TStore>>, Receiver<MessageTo<TStore>>)> {
         if self.remote_receiver.is_some() {
@@ -386,6 +438,7 @@ where
         let mut context = self.context.take().unwrap();
         let mrx = self.local_receiver.take().unwrap();
         let reply_to_main = self.run_configuration.local_sender.clone();
+        let linux_if_setup = self.linux_if_setup.clone();
 
         let _handle = thread::spawn(move || {
             let mut senders = HashMap::new();
@@ -398,7 +451,11 @@ where
             // the latter happens with message StartEngine (see below)
             context.execute_schedulers();
 
-            setup_kernel_interfaces(&context);
+            if let Err(errors) = setup_kernel_interfaces(&context, &mut linux_if_setup.lock().unwrap()) {
+                for (linux_if, e) in errors {
+                    error!("setting up kernel interface {} failed: {}", linux_if, e);
+                }
+            }
 
             // communicate with schedulers:
 
@@ -438,6 +495,9 @@ where
                         }
                         info!("terminating RunTime ...");
                         context.stop();
+                        if let Err(e) = linux_if_setup.lock().unwrap().teardown() {
+                            error!("tearing down kernel interfaces failed: {}", e);
+                        }
                         break;
                     }
                     Ok(MessageFrom::Task(pipeline_id, uuid, task_type)) => {
@@ -504,9 +564,15 @@ where
     }
 }
 
-pub fn setup_kernel_interfaces(context: &NetBricksContext) {
+/// sets up the linux interfaces of all KNI and virtio ports, a failing port does not stop the others,
+/// returns the failures together with the name of the linux interface
+pub fn setup_kernel_interfaces(
+    context: &NetBricksContext,
+    setup: &mut LinuxIfSetup,
+) -> Result<(), Vec<(String, LinuxIfError)>> {
     // set up kni: this requires the executable KniHandleRequest to run (serving rte_kni_handle_request)
     debug!("Number of PMD ports: {}", PmdPort::num_pmd_ports());
+    let mut errors = Vec::new();
     for port in context.ports.values() {
         debug!(
             "port {}:{} -- mac_address= {}",
@@ -539,16 +605,24 @@ pub fn setup_kernel_interfaces(context: &NetBricksContext) {
             };
             // kni interfaces w/o associated port are unusable
             if port.is_virtio() || associated_port.is_some() {
-                setup_linux_if(
+                if let Err(e) = setup_linux_if(
+                    setup,
                     port.linux_if().unwrap(),
                     &net_spec.ip_net.unwrap(),
                     &net_spec.mac.unwrap(),
                     &net_spec.nsname.unwrap(),
                     ip_address_count,
-                )
+                ) {
+                    errors.push((port.linux_if().unwrap().to_string(), e));
+                }
             };
         }
     }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
 }
 
 #[inline]
@@ -556,98 +630,19 @@ pub fn is_kni_core(pci: &CacheAligned<PortQueue>) -> bool {
     pci.rxq() == 0
 }
 
+/// sets the MAC address of the kernel interface, moves it into network namespace kni_netns and
+/// assigns ip_address_count consecutive IP addresses starting with ip_net, changes are recorded in setup
 pub fn setup_linux_if(
+    setup: &mut LinuxIfSetup,
     kni_name: &str,
     ip_net: &Ipv4Net,
     mac_address: &MacAddress,
     kni_netns: &String,
     ip_address_count: usize,
-) {
-    let ip_addr_first = ip_net.addr();
-    let prefix_len = ip_net.prefix_len();
-
+) -> Result<(), LinuxIfError> {
     debug!("setup_kni");
-    //# ip link set dev vEth1 address XX:XX:XX:XX:XX:XX
-    let output = Command::new("ip")
-        .args(&["link", "set", "dev", kni_name, "address", &mac_address.to_hex_string()])
-        .output()
-        .expect("failed to assign MAC address to kni i/f");
-    let reply = output.stderr;
-
-    debug!(
-        "assigning MAC addr {} to {}: {}, {}",
-        &mac_address.to_hex_string(),
-        kni_name,
-        output.status,
-        String::from_utf8_lossy(&reply)
-    );
-
-    //# ip netns add nskni
-    let output = Command::new("ip")
-        .args(&["netns", "add", kni_netns])
-        .output()
-        .expect("failed to create namespace for kni i/f");
-    let reply = output.stderr;
-
-    debug!(
-        "creating network namespace {}: {}, {}",
-        kni_netns,
-        output.status,
-        String::from_utf8_lossy(&reply)
-    );
-
-    // ip link set dev vEth1 netns nskni
-    let output = Command::new("ip")
-        .args(&["link", "set", "dev", kni_name, "netns", kni_netns])
-        .output()
-        .expect("failed to move kni i/f to namespace");
-    let reply = output.stderr;
-
-    debug!(
-        "moving kni i/f {} to namesapce {}: {}, {}",
-        kni_name,
-        kni_netns,
-        output.status,
-        String::from_utf8_lossy(&reply)
-    );
-    for i in 0..ip_address_count {
-        // e.g. ip netns exec nskni ip addr add w.x.y.z/24 dev vEth1
-        let ip_net = Ipv4Net::new(Ipv4Addr::from(u32::from(ip_addr_first) + i as u32), prefix_len)
-            .unwrap()
-            .to_string();
-        let output = Command::new("ip")
-            .args(&["netns", "exec", kni_netns, "ip", "addr", "add", &ip_net, "dev", kni_name])
-            .output()
-            .expect("failed to assign IP address to kni i/f");
-        let reply = output.stderr;
-        debug!(
-            "assigning IP addr {} to {}: {}, {}",
-            ip_net,
-            kni_name,
-            output.status,
-            String::from_utf8_lossy(&reply)
-        );
-    }
-    // e.g. ip netns exec nskni ip link set dev vEth1 up
-    let output1 = Command::new("ip")
-        .args(&["netns", "exec", kni_netns, "ip", "link", "set", "dev", kni_name, "up"])
-        .output()
-        .expect("failed to set kni i/f up");
-    let reply1 = output1.stderr;
-    debug!(
-        "ip netns exec {} ip link set dev {} up: {}, {}",
-        kni_netns,
-        kni_name,
-        output1.status,
-        String::from_utf8_lossy(&reply1)
-    );
-    // e.g. ip netns exec nskni ip addr show dev vEth1
-    let output2 = Command::new("ip")
-        .args(&["netns", "exec", kni_netns, "ip", "addr", "show", "dev", kni_name])
-        .output()
-        .expect("failed to show IP address of kni i/f");
-    let reply2 = output2.stdout;
-    info!("show IP addr: {}\n {}", output.status, String::from_utf8_lossy(&reply2));
+    setup.remove_leftover_netns(kni_netns)?;
+    setup.setup_interface(kni_name, ip_net, mac_address, kni_netns, ip_address_count)
 }
 
 pub fn physical_ports_for_core(core: i32, pmd_ports: &HashMap<String, Arc<PmdPort>>) -> Vec<&Arc<PmdPort>> {
@@ -727,8 +722,7 @@ pub fn new_port_queues_for_core(
 
 #[inline]
 fn get_tcp_port_base(port: &PmdPort, count: u16) -> u16 {
-    let port_mask = port.get_tcp_dst_port_mask();
-    port_mask - count * (!port_mask + 1)
+    tcp_port_base(port.get_tcp_dst_port_mask(), count)
 }
 
 #[inline]
@@ -795,6 +789,28 @@ pub fn prepare_checksum_and_ttl(p: &mut Pdu) {
     }
 }
 
+/// lowers the MSS option of SYN and SYN-ACK to max_mss or inserts it, if it is missing, returns true if p
+/// was changed. The checksums of a changed p are updated like in prepare_checksum_and_ttl, i.e. with
+/// tcp_checksum_tx_offload() only the pseudo header checksum and the header lengths for the offload are set,
+/// otherwise IP and TCP checksum are recalculated. The TTL is not changed.
+#[inline]
+pub fn clamp_mss(p: &mut Pdu, max_mss: u16) -> Result<bool, TcpOptionError> {
+    if !p.headers().tcp(2).syn_flag() {
+        return Ok(false);
+    }
+    let mss = tcp_options(p)?.mss;
+    match mss {
+        Some(mss) if mss <= max_mss => return Ok(false),
+        _ => (),
+    }
+    // an inserted MSS option needs four bytes
+    let reserve = if mss.is_none() { 4 } else { 0 };
+    update_ipv4_packet(p, reserve, |packet| {
+        clamp_parsed_mss_ipv4(packet, mss, max_mss).map(|(ip_len, _)| ip_len)
+    })?;
+    Ok(true)
+}
+
 #[inline]
 pub fn set_header(server: &L234Data, port: u16, p: &mut Pdu, me_mac: &MacAddress, me_ip: u32) {
     let stack = p.headers_mut();
@@ -882,8 +898,50 @@ pub fn strip_payload(p: &mut Pdu) {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use tcp_common::{set_tcp_options, TcpOptions};
+    use tcp_common::tests::{assert_pdu_checksums_valid, syn_pdu};
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    /// needs DPDK like the tests with Pdus in tcp_common
+    #[test]
+    #[ignore]
+    fn clamp_mss_of_pdu() {
+        // a SYN without options gets the MSS option
+        let mut p = syn_pdu(&[]);
+        assert_eq!(clamp_mss(&mut p, 1300), Ok(true));
+        assert_eq!(p.data_len(), 14 + 44);
+        assert_eq!(tcp_options(&p).unwrap().mss, Some(1300));
+        assert_pdu_checksums_valid(&p);
+
+        // a larger MSS is lowered in place, a smaller MSS is kept
+        let mut p = syn_pdu(b"data");
+        let options = TcpOptions {
+            mss: Some(1460),
+            window_scale: Some(7),
+            ..Default::default()
+        };
+        set_tcp_options(&mut p, &options).unwrap();
+        assert_eq!(clamp_mss(&mut p, 1400), Ok(true));
+        assert_eq!(p.data_len(), 14 + 48 + 4);
+        assert_eq!(
+            tcp_options(&p),
+            Ok(TcpOptions {
+                mss: Some(1400),
+                ..options
+            })
+        );
+        assert_eq!(tcp_payload_size(&p), 4);
+        assert_pdu_checksums_valid(&p);
+        assert_eq!(clamp_mss(&mut p, 1460), Ok(false));
+
+        // segments without SYN are not changed
+        p.headers_mut().tcp_mut(2).unset_syn_flag();
+        assert_eq!(clamp_mss(&mut p, 536), Ok(false));
+        assert_eq!(tcp_options(&p).unwrap().mss, Some(1400));
+    }
 }
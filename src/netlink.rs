@@ -0,0 +1,778 @@
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::thread;
+
+use eui48::MacAddress;
+use ipnet::Ipv4Net;
+use libc;
+
+/// directory of named network namespaces, as used by iproute2
+pub const NETNS_RUN_DIR: &str = "/var/run/netns";
+
+// see linux/netlink.h, linux/rtnetlink.h, linux/if_link.h and linux/if_addr.h
+const NLMSG_HDRLEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const RTM_NEWLINK: u16 = 16;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+const IFLA_ADDRESS: u16 = 1;
+const IFLA_NET_NS_FD: u16 = 28;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFF_UP: u32 = 0x1;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const NSFS_MAGIC: i64 = 0x6e73_6673;
+const RECV_BUFFER_SIZE: usize = 8192;
+
+#[derive(Debug)]
+pub enum NetlinkError {
+    /// a system call failed, e.g. socket, send, mount or setns
+    Io(io::Error),
+    /// the kernel rejected the request with this errno
+    Kernel(i32),
+    /// the reply of the kernel could not be parsed
+    Protocol(String),
+}
+
+impl NetlinkError {
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            NetlinkError::Io(e) => e.raw_os_error(),
+            NetlinkError::Kernel(errno) => Some(*errno),
+            NetlinkError::Protocol(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for NetlinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetlinkError::Io(e) => write!(f, "{}", e),
+            NetlinkError::Kernel(errno) => write!(f, "{} (errno {})", io::Error::from_raw_os_error(*errno), errno),
+            NetlinkError::Protocol(reason) => write!(f, "unexpected netlink reply: {}", reason),
+        }
+    }
+}
+
+impl Error for NetlinkError {}
+
+impl From<io::Error> for NetlinkError {
+    fn from(e: io::Error) -> NetlinkError {
+        NetlinkError::Io(e)
+    }
+}
+
+/// the step of setting up or tearing down a kernel interface which failed
+#[derive(Debug)]
+pub enum LinuxIfError {
+    NoSuchInterface(String),
+    OpenSocket(NetlinkError),
+    SetMacAddress(String, NetlinkError),
+    CreateNamespace(String, NetlinkError),
+    MoveToNamespace(String, NetlinkError),
+    EnterNamespace(String, NetlinkError),
+    AddAddress(Ipv4Net, NetlinkError),
+    /// the consecutive addresses starting with ip_net exceed 255.255.255.255
+    AddressRange(Ipv4Net, usize),
+    LinkUp(String, NetlinkError),
+    DeleteAddress(Ipv4Net, NetlinkError),
+    DeleteNamespace(String, NetlinkError),
+}
+
+impl fmt::Display for LinuxIfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinuxIfError::NoSuchInterface(name) => write!(f, "no interface {}", name),
+            LinuxIfError::OpenSocket(e) => write!(f, "opening netlink socket: {}", e),
+            LinuxIfError::SetMacAddress(name, e) => write!(f, "setting MAC address of {}: {}", name, e),
+            LinuxIfError::CreateNamespace(ns, e) => write!(f, "creating network namespace {}: {}", ns, e),
+            LinuxIfError::MoveToNamespace(name, e) => write!(f, "moving {} to network namespace: {}", name, e),
+            LinuxIfError::EnterNamespace(ns, e) => write!(f, "entering network namespace {}: {}", ns, e),
+            LinuxIfError::AddAddress(ip_net, e) => write!(f, "adding address {}: {}", ip_net, e),
+            LinuxIfError::AddressRange(ip_net, count) => {
+                write!(
+                    f,
+                    "{} addresses starting with {} exceed the IPv4 address space",
+                    count, ip_net
+                )
+            }
+            LinuxIfError::LinkUp(name, e) => write!(f, "setting {} up: {}", name, e),
+            LinuxIfError::DeleteAddress(ip_net, e) => write!(f, "deleting address {}: {}", ip_net, e),
+            LinuxIfError::DeleteNamespace(ns, e) => write!(f, "deleting network namespace {}: {}", ns, e),
+        }
+    }
+}
+
+impl Error for LinuxIfError {}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_ne_bytes(bytes)
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    let mut bytes = [0u8; 2];
+    bytes.copy_from_slice(&buf[offset..offset + 2]);
+    u16::from_ne_bytes(bytes)
+}
+
+/// a rtnetlink request: header, family specific struct and attributes
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn new(msg_type: u16, flags: u16) -> Message {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&0u32.to_ne_bytes()); // length, set by finish
+        buf.extend_from_slice(&msg_type.to_ne_bytes());
+        buf.extend_from_slice(&(flags | NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes()); // sequence number, set by finish
+        buf.extend_from_slice(&0u32.to_ne_bytes()); // port id, filled in by the kernel
+        Message { buf }
+    }
+
+    /// struct ifinfomsg
+    fn link(mut self, index: u32, flags: u32, change: u32) -> Message {
+        self.buf.extend_from_slice(&[libc::AF_UNSPEC as u8, 0]);
+        self.buf.extend_from_slice(&0u16.to_ne_bytes()); // device type
+        self.buf.extend_from_slice(&index.to_ne_bytes());
+        self.buf.extend_from_slice(&flags.to_ne_bytes());
+        self.buf.extend_from_slice(&change.to_ne_bytes());
+        self
+    }
+
+    /// struct ifaddrmsg
+    fn address(mut self, index: u32, prefix_len: u8) -> Message {
+        self.buf
+            .extend_from_slice(&[libc::AF_INET as u8, prefix_len, 0, RT_SCOPE_UNIVERSE]);
+        self.buf.extend_from_slice(&index.to_ne_bytes());
+        self
+    }
+
+    fn attribute(mut self, attr_type: u16, data: &[u8]) -> Message {
+        self.buf.extend_from_slice(&((4 + data.len()) as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&attr_type.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        // attributes are aligned to 4 bytes
+        while self.buf.len() % 4 != 0 {
+            self.buf.push(0);
+        }
+        self
+    }
+
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// a route netlink socket, it operates on the network namespace of the thread which opened it
+pub struct NetlinkSocket {
+    fd: RawFd,
+    seq: u32,
+}
+
+impl NetlinkSocket {
+    pub fn open() -> Result<NetlinkSocket, NetlinkError> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(NetlinkSocket { fd, seq: 0 })
+    }
+
+    /// sends the request and waits for the acknowledgement of the kernel
+    fn request(&mut self, message: Message) -> Result<(), NetlinkError> {
+        self.seq = self.seq.wrapping_add(1);
+        let buf = message.finish(self.seq);
+        let sent = unsafe { libc::send(self.fd, buf.as_ptr() as *const libc::c_void, buf.len(), 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let mut reply = [0u8; RECV_BUFFER_SIZE];
+        loop {
+            let received = unsafe { libc::recv(self.fd, reply.as_mut_ptr() as *mut libc::c_void, reply.len(), 0) };
+            if received < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e.into());
+            }
+            let received = received as usize;
+            let mut offset = 0;
+            while offset + NLMSG_HDRLEN <= received {
+                let len = read_u32(&reply, offset) as usize;
+                if len < NLMSG_HDRLEN || offset + len > received {
+                    return Err(NetlinkError::Protocol(format!("message length {}", len)));
+                }
+                if read_u16(&reply, offset + 4) == NLMSG_ERROR && read_u32(&reply, offset + 8) == self.seq {
+                    if len < NLMSG_HDRLEN + 4 {
+                        return Err(NetlinkError::Protocol("truncated error message".to_string()));
+                    }
+                    // an error code of 0 is the acknowledgement
+                    return match read_u32(&reply, offset + NLMSG_HDRLEN) as i32 {
+                        0 => Ok(()),
+                        error => Err(NetlinkError::Kernel(-error)),
+                    };
+                }
+                offset += (len + 3) & !3;
+            }
+        }
+    }
+
+    pub fn set_mac_address(&mut self, index: u32, mac_address: &MacAddress) -> Result<(), NetlinkError> {
+        self.request(
+            Message::new(RTM_NEWLINK, 0)
+                .link(index, 0, 0)
+                .attribute(IFLA_ADDRESS, mac_address.as_bytes()),
+        )
+    }
+
+    /// moves the link to the network namespace referred to by netns_fd
+    pub fn move_to_netns(&mut self, index: u32, netns_fd: RawFd) -> Result<(), NetlinkError> {
+        self.request(
+            Message::new(RTM_NEWLINK, 0)
+                .link(index, 0, 0)
+                .attribute(IFLA_NET_NS_FD, &(netns_fd as u32).to_ne_bytes()),
+        )
+    }
+
+    pub fn set_link_up(&mut self, index: u32) -> Result<(), NetlinkError> {
+        self.request(Message::new(RTM_NEWLINK, 0).link(index, IFF_UP, IFF_UP))
+    }
+
+    pub fn add_ipv4_address(&mut self, index: u32, ip_net: &Ipv4Net) -> Result<(), NetlinkError> {
+        let addr = ip_net.addr().octets();
+        self.request(
+            Message::new(RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL)
+                .address(index, ip_net.prefix_len())
+                .attribute(IFA_LOCAL, &addr)
+                .attribute(IFA_ADDRESS, &addr),
+        )
+    }
+
+    pub fn del_ipv4_address(&mut self, index: u32, ip_net: &Ipv4Net) -> Result<(), NetlinkError> {
+        self.request(
+            Message::new(RTM_DELADDR, 0)
+                .address(index, ip_net.prefix_len())
+                .attribute(IFA_LOCAL, &ip_net.addr().octets()),
+        )
+    }
+}
+
+impl Drop for NetlinkSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// index of the interface in the network namespace of the calling thread
+pub fn interface_index(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+/// count addresses with the prefix of ip_net, starting with the address of ip_net
+fn consecutive_ip_nets(ip_net: &Ipv4Net, count: usize) -> Result<Vec<Ipv4Net>, LinuxIfError> {
+    let first = u32::from(ip_net.addr());
+    if first as u64 + (count as u64).saturating_sub(1) > u32::max_value() as u64 {
+        return Err(LinuxIfError::AddressRange(*ip_net, count));
+    }
+    Ok((0..count)
+        .map(|i| Ipv4Net::new(Ipv4Addr::from(first + i as u32), ip_net.prefix_len()).unwrap())
+        .collect())
+}
+
+pub fn netns_path<P: AsRef<Path>>(run_dir: P, name: &str) -> PathBuf {
+    run_dir.as_ref().join(name)
+}
+
+/// true if a network namespace is bound to path
+fn is_netns(path: &Path) -> bool {
+    let c_path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(p) => p,
+        Err(_) => return false,
+    };
+    let mut stat: libc::statfs = unsafe { mem::zeroed() };
+    unsafe { libc::statfs(c_path.as_ptr(), &mut stat) == 0 && stat.f_type as i64 == NSFS_MAGIC }
+}
+
+fn join<R>(handle: thread::JoinHandle<Result<R, NetlinkError>>) -> Result<R, NetlinkError> {
+    handle
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "namespace thread panicked").into()))
+}
+
+/// creates a named network namespace like `ip netns add`, returns false if it exists already
+pub fn create_netns<P: AsRef<Path>>(run_dir: P, name: &str) -> Result<bool, NetlinkError> {
+    let path = netns_path(&run_dir, name);
+    if is_netns(&path) {
+        return Ok(false);
+    }
+    fs::create_dir_all(run_dir)?;
+    // the mount point, may be left over from a crashed run
+    File::create(&path)?;
+    let target = CString::new(path.as_os_str().as_bytes()).unwrap();
+    // unshare in a separate thread, so that the namespace of the caller stays untouched
+    let result = join(thread::spawn(move || {
+        if unsafe { libc::unshare(libc::CLONE_NEWNET) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+        let source = CString::new(format!("/proc/self/task/{}/ns/net", tid)).unwrap();
+        if unsafe {
+            libc::mount(
+                source.as_ptr(),
+                target.as_ptr(),
+                ptr::null(),
+                libc::MS_BIND,
+                ptr::null(),
+            )
+        } < 0
+        {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }));
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) => {
+            let _ = fs::remove_file(&path);
+            Err(e)
+        }
+    }
+}
+
+/// deletes a named network namespace like `ip netns delete`, a missing namespace is not an error
+pub fn delete_netns<P: AsRef<Path>>(run_dir: P, name: &str) -> Result<(), NetlinkError> {
+    let path = netns_path(run_dir, name);
+    if is_netns(&path) {
+        let target = CString::new(path.as_os_str().as_bytes()).unwrap();
+        if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    match fs::remove_file(&path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result.map_err(|e| e.into()),
+    }
+}
+
+/// runs f in a thread which has entered the named network namespace
+pub fn in_netns<P, F, R>(run_dir: P, name: &str, f: F) -> Result<R, NetlinkError>
+where
+    P: AsRef<Path>,
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let netns = File::open(netns_path(run_dir, name))?;
+    join(thread::spawn(move || {
+        if unsafe { libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(f())
+    }))
+}
+
+/// a change of the kernel network configuration made by LinuxIfSetup
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinuxIfChange {
+    /// a named network namespace
+    Namespace(String),
+    /// an IP address of an interface within a network namespace
+    Address {
+        netns: String,
+        name: String,
+        ip_net: Ipv4Net,
+    },
+}
+
+/// sets up kernel interfaces and records every change, so that it can be undone with teardown
+#[derive(Debug, Clone)]
+pub struct LinuxIfSetup {
+    run_dir: PathBuf,
+    changes: Vec<LinuxIfChange>,
+}
+
+impl Default for LinuxIfSetup {
+    fn default() -> LinuxIfSetup {
+        LinuxIfSetup::new(NETNS_RUN_DIR)
+    }
+}
+
+impl LinuxIfSetup {
+    pub fn new<P: AsRef<Path>>(run_dir: P) -> LinuxIfSetup {
+        LinuxIfSetup {
+            run_dir: run_dir.as_ref().to_path_buf(),
+            changes: Vec::new(),
+        }
+    }
+
+    pub fn changes(&self) -> &Vec<LinuxIfChange> {
+        &self.changes
+    }
+
+    fn record(&mut self, change: LinuxIfChange) {
+        if !self.changes.contains(&change) {
+            self.changes.push(change);
+        }
+    }
+
+    /// sets the MAC address of the interface, moves it into the network namespace netns, which is created if
+    /// necessary, assigns ip_address_count consecutive addresses starting with ip_net and sets the interface up.
+    /// Existing namespaces and addresses are reused, but only the ones created here are recorded for teardown,
+    /// see remove_leftover_netns for namespaces left over from a crashed run.
+    pub fn setup_interface(
+        &mut self,
+        name: &str,
+        ip_net: &Ipv4Net,
+        mac_address: &MacAddress,
+        netns: &str,
+        ip_address_count: usize,
+    ) -> Result<(), LinuxIfError> {
+        let mut socket = NetlinkSocket::open().map_err(LinuxIfError::OpenSocket)?;
+        let index = interface_index(name).ok_or_else(|| LinuxIfError::NoSuchInterface(name.to_string()))?;
+
+        socket
+            .set_mac_address(index, mac_address)
+            .map_err(|e| LinuxIfError::SetMacAddress(name.to_string(), e))?;
+        debug!("assigned MAC addr {} to {}", mac_address.to_hex_string(), name);
+
+        let created =
+            create_netns(&self.run_dir, netns).map_err(|e| LinuxIfError::CreateNamespace(netns.to_string(), e))?;
+        debug!(
+            "network namespace {} {}",
+            netns,
+            if created { "created" } else { "exists" }
+        );
+        if created {
+            self.record(LinuxIfChange::Namespace(netns.to_string()));
+        }
+
+        File::open(netns_path(&self.run_dir, netns))
+            .map_err(|e| e.into())
+            .and_then(|netns_file| socket.move_to_netns(index, netns_file.as_raw_fd()))
+            .map_err(|e| LinuxIfError::MoveToNamespace(name.to_string(), e))?;
+        debug!("moved {} to network namespace {}", name, netns);
+
+        let ip_nets = consecutive_ip_nets(ip_net, ip_address_count)?;
+        let if_name = name.to_string();
+        let (added, result) = in_netns(&self.run_dir, netns, move || {
+            let mut added = Vec::with_capacity(ip_nets.len());
+            let result = LinuxIfSetup::configure_in_netns(&if_name, &ip_nets, &mut added);
+            (added, result)
+        })
+        .map_err(|e| LinuxIfError::EnterNamespace(netns.to_string(), e))?;
+        for ip_net in added {
+            self.record(LinuxIfChange::Address {
+                netns: netns.to_string(),
+                name: name.to_string(),
+                ip_net,
+            });
+        }
+        result?;
+        info!(
+            "{} is up in network namespace {} with {} IP address(es) from {}",
+            name, netns, ip_address_count, ip_net
+        );
+        Ok(())
+    }
+
+    /// deletes the network namespace netns together with its interfaces and addresses if it exists but was not
+    /// created by this LinuxIfSetup, e.g. because a previous run crashed before its teardown, returns true if it
+    /// existed
+    pub fn remove_leftover_netns(&mut self, netns: &str) -> Result<bool, LinuxIfError> {
+        if self.changes.contains(&LinuxIfChange::Namespace(netns.to_string()))
+            || !is_netns(&netns_path(&self.run_dir, netns))
+        {
+            return Ok(false);
+        }
+        delete_netns(&self.run_dir, netns).map_err(|e| LinuxIfError::DeleteNamespace(netns.to_string(), e))?;
+        // addresses which we added to the namespace are gone with it
+        self.changes.retain(|change| match change {
+            LinuxIfChange::Address { netns: ns, .. } => ns != netns,
+            LinuxIfChange::Namespace(_) => true,
+        });
+        info!("deleted network namespace {} left over from a previous run", netns);
+        Ok(true)
+    }
+
+    /// runs within the network namespace, added receives the addresses which did not exist before
+    fn configure_in_netns(name: &str, ip_nets: &[Ipv4Net], added: &mut Vec<Ipv4Net>) -> Result<(), LinuxIfError> {
+        let mut socket = NetlinkSocket::open().map_err(LinuxIfError::OpenSocket)?;
+        let index = interface_index(name).ok_or_else(|| LinuxIfError::NoSuchInterface(name.to_string()))?;
+        for ip_net in ip_nets {
+            match socket.add_ipv4_address(index, ip_net) {
+                Ok(()) => {
+                    debug!("assigned IP addr {} to {}", ip_net, name);
+                    added.push(*ip_net);
+                }
+                Err(ref e) if e.errno() == Some(libc::EEXIST) => debug!("IP addr {} of {} exists", ip_net, name),
+                Err(e) => return Err(LinuxIfError::AddAddress(*ip_net, e)),
+            }
+        }
+        socket
+            .set_link_up(index)
+            .map_err(|e| LinuxIfError::LinkUp(name.to_string(), e))
+    }
+
+    /// undoes the recorded changes in reverse order, changes which are gone already are skipped, e.g. after a
+    /// crash. Changes which cannot be undone stay recorded and the first error is returned.
+    pub fn teardown(&mut self) -> Result<(), LinuxIfError> {
+        let mut first_error = None;
+        let mut remaining = Vec::new();
+        while let Some(change) = self.changes.pop() {
+            if let Err(e) = self.undo(&change) {
+                warn!("{}", e);
+                remaining.push(change);
+                if first_error.is_none() {
+                    first_error = Some(e);
+                }
+            }
+        }
+        remaining.reverse();
+        self.changes = remaining;
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn undo(&self, change: &LinuxIfChange) -> Result<(), LinuxIfError> {
+        match change {
+            LinuxIfChange::Address { netns, name, ip_net } => {
+                if !is_netns(&netns_path(&self.run_dir, netns)) {
+                    return Ok(());
+                }
+                let if_name = name.clone();
+                let ip_net = *ip_net;
+                in_netns(&self.run_dir, netns, move || {
+                    let index = match interface_index(&if_name) {
+                        Some(index) => index,
+                        None => return Ok(()),
+                    };
+                    NetlinkSocket::open().and_then(|mut socket| socket.del_ipv4_address(index, &ip_net))
+                })
+                .and_then(|result| result)
+                .or_else(|e| match e.errno() {
+                    Some(libc::EADDRNOTAVAIL) | Some(libc::ENODEV) => Ok(()),
+                    _ => Err(e),
+                })
+                .map_err(|e| LinuxIfError::DeleteAddress(ip_net, e))?;
+                debug!("removed IP addr {} from {}", ip_net, name);
+            }
+            LinuxIfChange::Namespace(netns) => {
+                delete_netns(&self.run_dir, netns).map_err(|e| LinuxIfError::DeleteNamespace(netns.clone(), e))?;
+                debug!("deleted network namespace {}", netns);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process::Command;
+
+    // set when the test binary runs in a fresh user, network and mount namespace
+    const IN_NAMESPACE: &str = "NETFCTS_IN_NAMESPACE";
+
+    /// re-executes the test within new namespaces and returns false, or returns true if we are there already
+    fn isolated(test: &str) -> bool {
+        if env::var(IN_NAMESPACE).is_ok() {
+            return true;
+        }
+        let unshare = ["--user", "--map-root-user", "--net", "--mount"];
+        match Command::new("unshare").args(&unshare).arg("true").status() {
+            Ok(status) if status.success() => (),
+            _ => {
+                println!("skipping {}, cannot create user namespace", test);
+                return false;
+            }
+        }
+        let status = Command::new("unshare")
+            .args(&unshare)
+            .arg(env::current_exe().unwrap())
+            .args(&["--exact", test, "--test-threads=1"])
+            .env(IN_NAMESPACE, "1")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        false
+    }
+
+    /// removes the directory at the end of the test, also if the test fails
+    struct RemoveDir(PathBuf);
+
+    impl Drop for RemoveDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn ip(args: &[&str]) -> String {
+        let output = Command::new("ip").args(args).output().unwrap();
+        assert!(
+            output.status.success(),
+            "ip {:?}: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).to_string()
+    }
+
+    #[test]
+    fn message_layout() {
+        let buf = Message::new(RTM_NEWLINK, 0)
+            .link(7, IFF_UP, IFF_UP)
+            .attribute(IFLA_ADDRESS, &[1, 2, 3, 4, 5, 6])
+            .finish(42);
+        // header + ifinfomsg + attribute of 4 + 6 bytes padded to 12
+        assert_eq!(buf.len(), 16 + 16 + 12);
+        assert_eq!(read_u32(&buf, 0) as usize, buf.len());
+        assert_eq!(read_u16(&buf, 4), RTM_NEWLINK);
+        assert_eq!(read_u16(&buf, 6), NLM_F_REQUEST | NLM_F_ACK);
+        assert_eq!(read_u32(&buf, 8), 42);
+        assert_eq!(read_u32(&buf, 20), 7);
+        assert_eq!(read_u16(&buf, 32), 10);
+        assert_eq!(read_u16(&buf, 34), IFLA_ADDRESS);
+        assert_eq!(&buf[36..42], &[1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn consecutive_addresses() {
+        let ip_net: Ipv4Net = "10.1.0.254/24".parse().unwrap();
+        assert_eq!(
+            consecutive_ip_nets(&ip_net, 3).unwrap(),
+            vec![
+                "10.1.0.254/24".parse::<Ipv4Net>().unwrap(),
+                "10.1.0.255/24".parse().unwrap(),
+                "10.1.1.0/24".parse().unwrap(),
+            ]
+        );
+        let ip_net: Ipv4Net = "255.255.255.254/24".parse().unwrap();
+        assert_eq!(consecutive_ip_nets(&ip_net, 2).unwrap().len(), 2);
+        match consecutive_ip_nets(&ip_net, 3) {
+            Err(LinuxIfError::AddressRange(net, 3)) => assert_eq!(net, ip_net),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn setup_veth() {
+        if !isolated("netlink::tests::setup_veth") {
+            return;
+        }
+        ip(&["link", "add", "veth0", "type", "veth", "peer", "name", "veth1"]);
+        let run_dir = env::temp_dir().join(format!("netfcts_netns_{}", std::process::id()));
+        let _remove_run_dir = RemoveDir(run_dir.clone());
+        let mac = MacAddress::parse_str("02:00:00:ab:cd:ef").unwrap();
+        let ip_net: Ipv4Net = "10.1.0.10/24".parse().unwrap();
+
+        let mut setup = LinuxIfSetup::new(&run_dir);
+        setup.setup_interface("veth0", &ip_net, &mac, "nstest", 3).unwrap();
+        assert_eq!(setup.changes().len(), 4);
+        assert_eq!(setup.changes()[0], LinuxIfChange::Namespace("nstest".to_string()));
+        assert!(interface_index("veth0").is_none());
+        let (link, addr) = in_netns(&run_dir, "nstest", || {
+            (
+                ip(&["-o", "link", "show", "dev", "veth0"]),
+                ip(&["-o", "addr", "show", "dev", "veth0"]),
+            )
+        })
+        .unwrap();
+        assert!(link.contains("02:00:00:ab:cd:ef"));
+        assert!(link.contains(",UP"));
+        for a in &["10.1.0.10/24", "10.1.0.11/24", "10.1.0.12/24"] {
+            assert!(addr.contains(a), "{} missing in {}", a, addr);
+        }
+
+        match setup.setup_interface("veth0", &ip_net, &mac, "nstest", 1) {
+            Err(LinuxIfError::NoSuchInterface(name)) => assert_eq!(name, "veth0"),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // existing addresses are reused, but not reported as added
+        let ip_net3: Ipv4Net = "10.1.0.13/24".parse().unwrap();
+        let added = in_netns(&run_dir, "nstest", move || {
+            let mut added = Vec::new();
+            LinuxIfSetup::configure_in_netns("veth0", &[ip_net, ip_net3], &mut added).unwrap();
+            added
+        })
+        .unwrap();
+        assert_eq!(added, vec![ip_net3]);
+
+        // an address which is gone already is skipped
+        in_netns(&run_dir, "nstest", || ip(&["addr", "del", "10.1.0.11/24", "dev", "veth0"])).unwrap();
+        setup.teardown().unwrap();
+        assert!(setup.changes().is_empty());
+        assert!(!netns_path(&run_dir, "nstest").exists());
+        // teardown is idempotent
+        setup.teardown().unwrap();
+
+        // a run which crashes before its teardown leaves the namespace behind
+        ip(&["link", "add", "veth2", "type", "veth", "peer", "name", "veth3"]);
+        let mut crashed = LinuxIfSetup::new(&run_dir);
+        crashed.setup_interface("veth2", &ip_net, &mac, "nstest", 1).unwrap();
+        drop(crashed);
+        assert!(netns_path(&run_dir, "nstest").exists());
+
+        // the next run removes it before its setup and removes its own namespace in the teardown
+        let mut setup_after_crash = LinuxIfSetup::new(&run_dir);
+        assert!(setup_after_crash.remove_leftover_netns("nstest").unwrap());
+        assert!(!netns_path(&run_dir, "nstest").exists());
+        assert!(!setup_after_crash.remove_leftover_netns("nstest").unwrap());
+        ip(&["link", "add", "veth4", "type", "veth", "peer", "name", "veth5"]);
+        let ip_net1: Ipv4Net = "10.1.1.10/24".parse().unwrap();
+        setup_after_crash
+            .setup_interface("veth4", &ip_net1, &mac, "nstest", 1)
+            .unwrap();
+        assert_eq!(
+            setup_after_crash.changes(),
+            &vec![
+                LinuxIfChange::Namespace("nstest".to_string()),
+                LinuxIfChange::Address {
+                    netns: "nstest".to_string(),
+                    name: "veth4".to_string(),
+                    ip_net: ip_net1,
+                },
+            ]
+        );
+        // the namespace created by this run is not a leftover
+        assert!(!setup_after_crash.remove_leftover_netns("nstest").unwrap());
+        setup_after_crash.teardown().unwrap();
+        assert!(setup_after_crash.changes().is_empty());
+        assert!(!netns_path(&run_dir, "nstest").exists());
+    }
+}
@@ -1,10 +1,13 @@
 use std::fmt;
+use std::io::{self, Read, Write};
 use std::slice::Iter;
 use std::cmp;
 use std::fmt::Display;
 use std::cell::RefCell;
 use std::rc::Rc;
-use conrecord::{ConRecord, HasConData, HasTcpState};
+use conrecord::{ConCounters, ConRecord, HasConData, HasTcpState};
+use export::{write_rows, ConRecordRow, ExportFormat};
+use system::SystemData;
 use {ReleaseCause};
 use TcpState;
 
@@ -19,6 +22,146 @@ pub trait SimpleStore: Send {
     fn get_mut(&mut self, slot: usize) -> &mut ConRecord;
 }
 
+/// stores which keep ConCounters next to the ConRecords
+pub trait CounterStore: SimpleStore {
+    fn get_counters(&self, slot: usize) -> &ConCounters;
+    fn get_counters_mut(&mut self, slot: usize) -> &mut ConCounters;
+}
+
+/// first bytes of a binary dump of a RecordStore
+pub const DUMP_MAGIC: [u8; 4] = *b"NFRS";
+/// 2: ConRecord without payload packet counters, 3: ConRecord with handshake RTT,
+/// dumps of older versions are still read by read_from
+pub const DUMP_VERSION: u16 = 3;
+const DUMP_HEADER_SIZE: usize = 52;
+/// largest capacity accepted by read_from, protects against allocating memory for corrupted headers
+pub const MAX_DUMP_CAPACITY: u64 = 1 << 26;
+
+/// records which can be written to a binary dump, all integers are little endian
+pub trait BinaryRecord: Sized {
+    /// identifies the record type in the dump
+    const RECORD_TYPE: u16;
+    /// bytes written by write_record
+    const RECORD_SIZE: usize;
+    fn write_record(&self, buf: &mut Vec<u8>);
+    /// size of the records in dumps of version, None if dumps of this version cannot be read
+    fn record_size(version: u16) -> Option<usize> {
+        if version == DUMP_VERSION {
+            Some(Self::RECORD_SIZE)
+        } else {
+            None
+        }
+    }
+    /// reads a record of a dump of version from record_size(version) bytes
+    fn read_record(reader: &mut RecordReader, version: u16) -> Result<Self, String>;
+}
+
+/// reads little endian integers from the bytes of a record
+pub struct RecordReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> RecordReader<'a> {
+    pub fn new(buf: &'a [u8]) -> RecordReader<'a> {
+        RecordReader { buf, pos: 0 }
+    }
+
+    #[inline]
+    fn take(&mut self, n: usize) -> &'a [u8] {
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        bytes
+    }
+
+    #[inline]
+    pub fn u8(&mut self) -> u8 {
+        self.take(1)[0]
+    }
+
+    #[inline]
+    pub fn u16(&mut self) -> u16 {
+        let mut bytes = [0u8; 2];
+        bytes.copy_from_slice(self.take(2));
+        u16::from_le_bytes(bytes)
+    }
+
+    #[inline]
+    pub fn u32(&mut self) -> u32 {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.take(4));
+        u32::from_le_bytes(bytes)
+    }
+
+    #[inline]
+    pub fn u64(&mut self) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8));
+        u64::from_le_bytes(bytes)
+    }
+}
+
+/// the header of a binary dump, layout (little endian):
+/// magic [u8; 4], version u16, record type u16, record size u32, capacity u64, number of records u64,
+/// record count u64, overflow count u64, cpu clock (TSC frequency in Hz) u64, followed by the records
+#[derive(Debug, Clone, PartialEq)]
+pub struct DumpHeader {
+    pub version: u16,
+    pub record_type: u16,
+    pub record_size: u32,
+    pub capacity: u64,
+    /// records in the dump
+    pub records: u64,
+    /// records stored since start, including overwritten records
+    pub record_count: u64,
+    pub overflow_count: u64,
+    /// TSC frequency of the system which recorded the time stamps
+    pub cpu_clock: u64,
+}
+
+impl DumpHeader {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(DUMP_HEADER_SIZE);
+        buf.extend_from_slice(&DUMP_MAGIC);
+        buf.extend_from_slice(&self.version.to_le_bytes());
+        buf.extend_from_slice(&self.record_type.to_le_bytes());
+        buf.extend_from_slice(&self.record_size.to_le_bytes());
+        for v in &[
+            self.capacity,
+            self.records,
+            self.record_count,
+            self.overflow_count,
+            self.cpu_clock,
+        ] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        w.write_all(&buf)
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<DumpHeader> {
+        let mut buf = [0u8; DUMP_HEADER_SIZE];
+        r.read_exact(&mut buf)?;
+        if buf[0..4] != DUMP_MAGIC {
+            return Err(invalid_data("not a record store dump".to_string()));
+        }
+        let mut reader = RecordReader::new(&buf[4..]);
+        Ok(DumpHeader {
+            version: reader.u16(),
+            record_type: reader.u16(),
+            record_size: reader.u32(),
+            capacity: reader.u64(),
+            records: reader.u64(),
+            record_count: reader.u64(),
+            overflow_count: reader.u64(),
+            cpu_clock: reader.u64(),
+        })
+    }
+}
+
+fn invalid_data(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
 #[derive(Clone)]
 pub struct RecordStore<T: Storable> {
     store: Vec<T>,
@@ -91,6 +234,90 @@ impl<T: Storable> RecordStore<T> {
     }
 }
 
+impl<T: Storable + BinaryRecord> RecordStore<T> {
+    /// writes the records in a compact binary format, see DumpHeader, w should be buffered
+    pub fn write_to<W: Write>(&self, w: &mut W, system_data: &SystemData) -> io::Result<()> {
+        DumpHeader {
+            version: DUMP_VERSION,
+            record_type: T::RECORD_TYPE,
+            record_size: T::RECORD_SIZE as u32,
+            capacity: self.store.len() as u64,
+            records: self.len() as u64,
+            record_count: self.record_count as u64,
+            overflow_count: self.overflow_count as u64,
+            cpu_clock: system_data.cpu_clock,
+        }
+        .write(w)?;
+        let mut buf = Vec::with_capacity(T::RECORD_SIZE);
+        for record in self.iter() {
+            buf.clear();
+            record.write_record(&mut buf);
+            debug_assert_eq!(buf.len(), T::RECORD_SIZE);
+            w.write_all(&buf)?;
+        }
+        Ok(())
+    }
+
+    /// reads a dump written by write_to, r should be buffered
+    pub fn read_from<R: Read>(mut r: R) -> io::Result<(RecordStore<T>, DumpHeader)> {
+        let header = DumpHeader::read(&mut r)?;
+        let record_size = match T::record_size(header.version) {
+            Some(record_size) => record_size,
+            None => return Err(invalid_data(format!("unsupported dump version {}", header.version))),
+        };
+        if header.record_type != T::RECORD_TYPE || header.record_size as usize != record_size {
+            return Err(invalid_data(format!(
+                "dump contains records of type {} and size {}, expected type {} and size {}",
+                header.record_type,
+                header.record_size,
+                T::RECORD_TYPE,
+                record_size
+            )));
+        }
+        if header.capacity == 0 || header.capacity > MAX_DUMP_CAPACITY {
+            return Err(invalid_data(format!(
+                "invalid capacity {}, expected 1 to {}",
+                header.capacity, MAX_DUMP_CAPACITY
+            )));
+        }
+        // get_next_slot requires overflow_count <= record_count <= overflow_count + capacity,
+        // and len() expects all records to be in the dump
+        if header.overflow_count > header.record_count
+            || header.record_count - header.overflow_count > header.capacity
+            || header.records != cmp::min(header.record_count, header.capacity)
+        {
+            return Err(invalid_data(format!(
+                "inconsistent header: {} records, capacity {}, record count {}, overflow count {}",
+                header.records, header.capacity, header.record_count, header.overflow_count
+            )));
+        }
+        // read the records before allocating the store, a truncated dump fails early
+        let mut records = Vec::new();
+        let mut buf = vec![0u8; record_size];
+        for slot in 0..header.records as usize {
+            r.read_exact(&mut buf)?;
+            records.push(
+                T::read_record(&mut RecordReader::new(&buf), header.version)
+                    .map_err(|e| invalid_data(format!("record {}: {}", slot, e)))?,
+            );
+        }
+        records.resize(header.capacity as usize, T::new());
+        let store = RecordStore {
+            store: records,
+            record_count: header.record_count as usize,
+            overflow_count: header.overflow_count as usize,
+        };
+        Ok((store, header))
+    }
+}
+
+impl RecordStore<ConRecord> {
+    /// writes one row per connection, returns the number of rows
+    pub fn export<W: Write>(&self, w: &mut W, format: ExportFormat, system_data: &SystemData) -> io::Result<usize> {
+        write_rows(w, format, false, self.iter().map(|c| ConRecordRow::new(c, system_data)))
+    }
+}
+
 /// we need trait SimpleStore for the ConRecordOperations trait
 impl SimpleStore for RecordStore<ConRecord> {
     #[inline]
@@ -104,9 +331,11 @@ impl SimpleStore for RecordStore<ConRecord> {
     }
 }
 
+/// stores a ConRecord, the ConCounters and a record of type T for each connection
 #[derive(Clone)]
 pub struct Store64<T: Storable> {
     store_0: Vec<ConRecord>,
+    counters: Vec<ConCounters>,
     store_1: Vec<T>,
     record_count: usize,
     overflow_count: usize,
@@ -116,12 +345,14 @@ impl<T: Storable> Store64<T> {
     pub fn with_capacity(capacity: usize) -> Store64<T> {
         Store64 {
             store_0: vec![ConRecord::new(); capacity],
+            counters: vec![ConCounters::new(); capacity],
             store_1: vec![T::new(); capacity],
             record_count: 0,
             overflow_count: 0,
         }
     }
 
+    /// the counters of the slot are reset
     #[inline]
     pub fn get_next_slot(&mut self) -> usize {
         if self.record_count - self.overflow_count == self.store_0.len() {
@@ -135,7 +366,9 @@ impl<T: Storable> Store64<T> {
             self.overflow_count = self.record_count;
         }
         self.record_count += 1;
-        self.record_count - 1 - self.overflow_count
+        let slot = self.record_count - 1 - self.overflow_count;
+        self.counters[slot] = ConCounters::new();
+        slot
     }
 
     #[inline]
@@ -157,6 +390,11 @@ impl<T: Storable> Store64<T> {
         self.iter_0().zip(self.iter_1())
     }
 
+    #[inline]
+    pub fn iter_counters(&self) -> Iter<ConCounters> {
+        self.counters[0..self.len()].iter()
+    }
+
     #[inline]
     pub fn get_mut_1(&mut self, slot: usize) -> &mut T {
         &mut self.store_1[slot]
@@ -167,6 +405,7 @@ impl<T: Storable> Store64<T> {
         &self.store_1[slot]
     }
 
+    /// sorts the ConRecords only, the ConCounters and second records keep their slots
     pub fn sort_0_by<F>(&mut self, compare: F)
     where
         F: FnMut(&ConRecord, &ConRecord) -> cmp::Ordering,
@@ -176,6 +415,22 @@ impl<T: Storable> Store64<T> {
     }
 }
 
+impl<T: Storable> Store64<T> {
+    /// writes one row per connection, the Display of the second record goes into the column data
+    pub fn export<W: Write>(&self, w: &mut W, format: ExportFormat, system_data: &SystemData) -> io::Result<usize> {
+        write_rows(
+            w,
+            format,
+            true,
+            self.iter().zip(self.iter_counters()).map(|((c, t), counters)| {
+                ConRecordRow::new(c, system_data)
+                    .with_counters(counters, system_data)
+                    .with_data(t)
+            }),
+        )
+    }
+}
+
 impl<T: Storable> SimpleStore for Store64<T> {
     #[inline]
     fn get(&self, slot: usize) -> &ConRecord {
@@ -188,10 +443,22 @@ impl<T: Storable> SimpleStore for Store64<T> {
     }
 }
 
+impl<T: Storable> CounterStore for Store64<T> {
+    #[inline]
+    fn get_counters(&self, slot: usize) -> &ConCounters {
+        &self.counters[slot]
+    }
+
+    #[inline]
+    fn get_counters_mut(&mut self, slot: usize) -> &mut ConCounters {
+        &mut self.counters[slot]
+    }
+}
+
 impl<T: Storable> fmt::Debug for Store64<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for i in 0..self.len() - 1 {
-            write!(f, "({}, {})", self.store_0[i], self.store_1[i])?;
+            write!(f, "({}, {}, {})", self.store_0[i], self.counters[i], self.store_1[i])?;
         }
         Ok(())
     }
@@ -224,24 +491,192 @@ pub trait ConRecordOperations<S: SimpleStore> {
             .set_server_index(index as u8)
     }
 
+    /// the counters are kept in a CounterStore, e.g. a Store64
+    #[inline]
+    fn sent_payload_pkts(&self) -> usize
+    where
+        S: CounterStore,
+    {
+        self.store()
+            .borrow()
+            .get_counters(self.con_rec())
+            .sent_payload_packets() as usize
+    }
+
+    #[inline]
+    fn recv_payload_pkts(&self) -> usize
+    where
+        S: CounterStore,
+    {
+        self.store()
+            .borrow()
+            .get_counters(self.con_rec())
+            .recv_payload_packets() as usize
+    }
+
+    #[inline]
+    fn inc_sent_payload_pkts(&self) -> usize
+    where
+        S: CounterStore,
+    {
+        self.store()
+            .borrow_mut()
+            .get_counters_mut(self.con_rec())
+            .inc_sent_payload_pkts() as usize
+    }
+
+    #[inline]
+    fn inc_recv_payload_pkts(&self) -> usize
+    where
+        S: CounterStore,
+    {
+        self.store()
+            .borrow_mut()
+            .get_counters_mut(self.con_rec())
+            .inc_recv_payload_pkts() as usize
+    }
+
+    #[inline]
+    fn sent_payload_bytes(&self) -> u64
+    where
+        S: CounterStore,
+    {
+        self.store().borrow().get_counters(self.con_rec()).sent_payload_bytes()
+    }
+
+    #[inline]
+    fn recv_payload_bytes(&self) -> u64
+    where
+        S: CounterStore,
+    {
+        self.store().borrow().get_counters(self.con_rec()).recv_payload_bytes()
+    }
+
+    #[inline]
+    fn sent_packets(&self) -> usize
+    where
+        S: CounterStore,
+    {
+        self.store().borrow().get_counters(self.con_rec()).sent_packets() as usize
+    }
+
+    #[inline]
+    fn recv_packets(&self) -> usize
+    where
+        S: CounterStore,
+    {
+        self.store().borrow().get_counters(self.con_rec()).recv_packets() as usize
+    }
+
+    #[inline]
+    fn count_sent_packet(&self, payload_size: usize)
+    where
+        S: CounterStore,
+    {
+        self.store()
+            .borrow_mut()
+            .get_counters_mut(self.con_rec())
+            .count_sent_packet(payload_size)
+    }
+
+    #[inline]
+    fn count_recv_packet(&self, payload_size: usize)
+    where
+        S: CounterStore,
+    {
+        self.store()
+            .borrow_mut()
+            .get_counters_mut(self.con_rec())
+            .count_recv_packet(payload_size)
+    }
+
+    #[inline]
+    fn sent_retransmissions(&self) -> usize
+    where
+        S: CounterStore,
+    {
+        self.store()
+            .borrow()
+            .get_counters(self.con_rec())
+            .sent_retransmissions() as usize
+    }
+
+    #[inline]
+    fn recv_retransmissions(&self) -> usize
+    where
+        S: CounterStore,
+    {
+        self.store()
+            .borrow()
+            .get_counters(self.con_rec())
+            .recv_retransmissions() as usize
+    }
+
+    #[inline]
+    fn inc_sent_retransmissions(&self) -> usize
+    where
+        S: CounterStore,
+    {
+        self.store()
+            .borrow_mut()
+            .get_counters_mut(self.con_rec())
+            .inc_sent_retransmissions() as usize
+    }
+
+    #[inline]
+    fn inc_recv_retransmissions(&self) -> usize
+    where
+        S: CounterStore,
+    {
+        self.store()
+            .borrow_mut()
+            .get_counters_mut(self.con_rec())
+            .inc_recv_retransmissions() as usize
+    }
+
     #[inline]
-    fn sent_payload_pkts(&self) -> usize {
-        self.store().borrow().get(self.con_rec()).sent_payload_packets() as usize
+    fn handshake_rtt(&self) -> Option<u64> {
+        self.store().borrow().get(self.con_rec()).handshake_rtt()
     }
 
     #[inline]
-    fn recv_payload_pkts(&self) -> usize {
-        self.store().borrow().get(self.con_rec()).recv_payload_packets() as usize
+    fn set_handshake_rtt(&self, rtt: u64) {
+        self.store().borrow_mut().get_mut(self.con_rec()).set_handshake_rtt(rtt)
     }
 
     #[inline]
-    fn inc_sent_payload_pkts(&self) -> usize {
-        self.store().borrow_mut().get_mut(self.con_rec()).inc_sent_payload_pkts() as usize
+    fn add_rtt_sample(&self, rtt: u64)
+    where
+        S: CounterStore,
+    {
+        self.store()
+            .borrow_mut()
+            .get_counters_mut(self.con_rec())
+            .add_rtt_sample(rtt)
     }
 
     #[inline]
-    fn inc_recv_payload_pkts(&self) -> usize {
-        self.store().borrow_mut().get_mut(self.con_rec()).inc_recv_payload_pkts() as usize
+    fn srtt(&self) -> Option<u64>
+    where
+        S: CounterStore,
+    {
+        self.store().borrow().get_counters(self.con_rec()).srtt()
+    }
+
+    #[inline]
+    fn rttvar(&self) -> Option<u64>
+    where
+        S: CounterStore,
+    {
+        self.store().borrow().get_counters(self.con_rec()).rttvar()
+    }
+
+    #[inline]
+    fn rtt_samples(&self) -> usize
+    where
+        S: CounterStore,
+    {
+        self.store().borrow().get_counters(self.con_rec()).rtt_samples() as usize
     }
 
     #[inline]
@@ -249,6 +684,11 @@ pub trait ConRecordOperations<S: SimpleStore> {
         self.store().borrow().get(self.con_rec()).states()
     }
 
+    #[inline]
+    fn dropped_states(&self) -> usize {
+        self.store().borrow().get(self.con_rec()).dropped_states()
+    }
+
     #[inline]
     fn set_release_cause(&self, cause: ReleaseCause) {
         self.store().borrow_mut().get_mut(self.con_rec()).set_release_cause(cause)
@@ -284,3 +724,222 @@ pub trait ConRecordOperations<S: SimpleStore> {
         self.store().borrow().get(self.con_rec()).uid()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use TcpRole;
+
+    fn system_data() -> SystemData {
+        SystemData {
+            cpu_clock: 2_400_000_000,
+            topology: Default::default(),
+        }
+    }
+
+    fn store_with_records(capacity: usize, n: usize) -> RecordStore<ConRecord> {
+        let mut store = RecordStore::<ConRecord>::with_capacity(capacity);
+        for i in 0..n {
+            let slot = store.get_next_slot();
+            let c = store.get_mut(slot);
+            c.init(TcpRole::Client, 1000 + i as u16, Some((0x0a00_0001 + i as u32, 80)));
+            c.push_state(TcpState::SynSent);
+            c.push_state(TcpState::Established);
+            c.push_state(TcpState::FinWait1);
+            c.set_handshake_rtt(10_000 * i as u64);
+            c.set_release_cause(ReleaseCause::ActiveClose);
+            c.set_server_index(i as u8);
+        }
+        store
+    }
+
+    #[test]
+    fn store64_counters() {
+        let mut store = Store64::<ConCounters>::with_capacity(2);
+        for _ in 0..2 {
+            let slot = store.get_next_slot();
+            store.get_counters_mut(slot).count_sent_packet(100);
+            store.get_counters_mut(slot).add_rtt_sample(10_000);
+        }
+        assert_eq!(store.get_counters(1).sent_payload_bytes(), 100);
+        assert_eq!(store.get_counters(1).rtt_samples(), 1);
+        // wraps, a reused slot starts with fresh counters
+        let slot = store.get_next_slot();
+        assert_eq!(slot, 0);
+        assert_eq!(store.get_counters(0).sent_packets(), 0);
+        assert_eq!(store.get_counters(0).srtt(), None);
+        assert_eq!(store.iter_counters().count(), 2);
+    }
+
+    #[test]
+    fn dump_and_reload() {
+        // wraps once
+        let store = store_with_records(100, 150);
+        let mut dump = Vec::new();
+        store.write_to(&mut dump, &system_data()).unwrap();
+        assert_eq!(dump.len(), DUMP_HEADER_SIZE + 100 * ConRecord::RECORD_SIZE);
+
+        let (reloaded, header) = RecordStore::<ConRecord>::read_from(Cursor::new(&dump)).unwrap();
+        assert_eq!(header.record_type, ConRecord::RECORD_TYPE);
+        assert_eq!(header.records, 100);
+        assert_eq!(header.record_count, 150);
+        assert_eq!(header.overflow_count, 100);
+        assert_eq!(header.cpu_clock, 2_400_000_000);
+        assert_eq!(reloaded.len(), store.len());
+        for (a, b) in store.iter().zip(reloaded.iter()) {
+            assert_eq!(a.to_string(), b.to_string());
+            assert_eq!(a.uid(), b.uid());
+            assert_eq!(a.server_state(), b.server_state());
+            assert_eq!(a.handshake_rtt(), b.handshake_rtt());
+        }
+
+        let mut again = Vec::new();
+        reloaded.write_to(&mut again, &system_data()).unwrap();
+        assert_eq!(dump, again);
+    }
+
+    /// converts a dump of the current version to the layout of version 1 or 2
+    fn downgrade_dump(dump: &[u8], version: u16) -> Vec<u8> {
+        let record_size = ConRecord::record_size(version).unwrap();
+        let mut old = dump[..DUMP_HEADER_SIZE].to_vec();
+        old[4..6].copy_from_slice(&version.to_le_bytes());
+        old[8..12].copy_from_slice(&(record_size as u32).to_le_bytes());
+        for record in dump[DUMP_HEADER_SIZE..].chunks(ConRecord::RECORD_SIZE) {
+            old.extend_from_slice(&record[0..40]);
+            if version == 1 {
+                // payload packet counters as u16
+                old.extend_from_slice(&[1, 0, 2, 0]);
+            }
+            old.extend_from_slice(&record[44..]);
+        }
+        old
+    }
+
+    #[test]
+    fn reload_old_dump_versions() {
+        let store = store_with_records(10, 5);
+        let mut dump = Vec::new();
+        store.write_to(&mut dump, &system_data()).unwrap();
+
+        for &version in &[1, 2] {
+            let old = downgrade_dump(&dump, version);
+            assert_eq!(
+                old.len(),
+                DUMP_HEADER_SIZE + 5 * ConRecord::record_size(version).unwrap()
+            );
+            let (reloaded, header) = RecordStore::<ConRecord>::read_from(Cursor::new(&old)).unwrap();
+            assert_eq!(header.version, version);
+            assert_eq!(reloaded.len(), 5);
+            for (a, b) in store.iter().zip(reloaded.iter()) {
+                assert_eq!(a.uid(), b.uid());
+                assert_eq!(a.sock(), b.sock());
+                assert_eq!(a.states(), b.states());
+                assert_eq!(a.release_cause(), b.release_cause());
+                assert_eq!(a.server_index(), b.server_index());
+                assert_eq!(b.handshake_rtt(), None);
+            }
+        }
+
+        let mut unknown_version = dump.clone();
+        unknown_version[4..6].copy_from_slice(&(DUMP_VERSION + 1).to_le_bytes());
+        let e = RecordStore::<ConRecord>::read_from(Cursor::new(&unknown_version))
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reload_invalid_dumps() {
+        let store = store_with_records(10, 5);
+        let mut dump = Vec::new();
+        store.write_to(&mut dump, &system_data()).unwrap();
+
+        let truncated = &dump[..dump.len() - 1];
+        let e = RecordStore::<ConRecord>::read_from(Cursor::new(truncated))
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut wrong_magic = dump.clone();
+        wrong_magic[0] = b'X';
+        let e = RecordStore::<ConRecord>::read_from(Cursor::new(&wrong_magic))
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let mut wrong_type = dump.clone();
+        wrong_type[6] = 0xff;
+        let e = RecordStore::<ConRecord>::read_from(Cursor::new(&wrong_type))
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // first state of the first record
+        let mut wrong_record = dump.clone();
+        wrong_record[DUMP_HEADER_SIZE + 52] = 0xff;
+        let e = RecordStore::<ConRecord>::read_from(Cursor::new(&wrong_record))
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // release_cause, role and server_state of the first record
+        for &offset in &[61, 62, 63] {
+            let mut wrong_record = dump.clone();
+            wrong_record[DUMP_HEADER_SIZE + offset] = 0xff;
+            let e = RecordStore::<ConRecord>::read_from(Cursor::new(&wrong_record))
+                .err()
+                .unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+
+        // capacity of 0 and capacity too large
+        for &capacity in &[0, MAX_DUMP_CAPACITY + 1, u64::max_value()] {
+            let mut wrong_capacity = dump.clone();
+            wrong_capacity[12..20].copy_from_slice(&capacity.to_le_bytes());
+            let e = RecordStore::<ConRecord>::read_from(Cursor::new(&wrong_capacity))
+                .err()
+                .unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    /// sets records, record_count and overflow_count of the header of a dump
+    fn with_counts(dump: &[u8], records: u64, record_count: u64, overflow_count: u64) -> Vec<u8> {
+        let mut dump = dump.to_vec();
+        dump[20..28].copy_from_slice(&records.to_le_bytes());
+        dump[28..36].copy_from_slice(&record_count.to_le_bytes());
+        dump[36..44].copy_from_slice(&overflow_count.to_le_bytes());
+        dump
+    }
+
+    #[test]
+    fn reload_inconsistent_headers() {
+        // capacity 10, 5 records
+        let store = store_with_records(10, 5);
+        let mut dump = Vec::new();
+        store.write_to(&mut dump, &system_data()).unwrap();
+        assert!(RecordStore::<ConRecord>::read_from(Cursor::new(&with_counts(&dump, 5, 5, 0))).is_ok());
+
+        let reload_error = |dump: Vec<u8>| {
+            RecordStore::<ConRecord>::read_from(Cursor::new(&dump))
+                .err()
+                .unwrap()
+                .kind()
+        };
+        // overflow count larger than record count, get_next_slot would underflow
+        assert_eq!(reload_error(with_counts(&dump, 5, 5, 6)), io::ErrorKind::InvalidData);
+        // more records since the last wrap than capacity, get_next_slot would return slots beyond the store
+        assert_eq!(reload_error(with_counts(&dump, 5, 25, 10)), io::ErrorKind::InvalidData);
+        // fewer records than record count, the missing records would be blank
+        assert_eq!(reload_error(with_counts(&dump, 5, 6, 0)), io::ErrorKind::InvalidData);
+        // fewer records than capacity after a wrap
+        assert_eq!(reload_error(with_counts(&dump, 5, 15, 10)), io::ErrorKind::InvalidData);
+        // more records than record count or capacity
+        assert_eq!(reload_error(with_counts(&dump, 5, 4, 0)), io::ErrorKind::InvalidData);
+        let store = store_with_records(4, 4);
+        let mut dump = Vec::new();
+        store.write_to(&mut dump, &system_data()).unwrap();
+        assert_eq!(reload_error(with_counts(&dump, 5, 5, 0)), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,24 +1,693 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt;
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::Read;
+use std::thread;
 use eui48::{MacAddress, ParseError};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use clock::{Clock, TscClock};
+use libc;
 
 const CPU_CLOCK_PATH: &str = "/sys/devices/system/cpu/cpu0/cpufreq/cpuinfo_max_freq";
+const CPU_INFO_PATH: &str = "/proc/cpuinfo";
+const SYSFS_ROOT: &str = "/sys";
+
+// parameters for measuring the TSC frequency
+const CALIBRATION_ROUNDS: usize = 3;
+const CALIBRATION_INTERVAL_MILLIS: u64 = 20;
+const MIN_PLAUSIBLE_TSC_HZ: u64 = 100_000_000;
+const MAX_PLAUSIBLE_TSC_HZ: u64 = 10_000_000_000;
+
+#[derive(Debug)]
+pub enum SystemError {
+    /// a file in /proc or /sys could not be read
+    Io(String, io::Error),
+    /// a file in /proc or /sys has unexpected content
+    Parse(String, String),
+    /// the TSC does not tick at a constant rate
+    NoInvariantTsc,
+    /// measuring the TSC against CLOCK_MONOTONIC_RAW gave no plausible result
+    CalibrationFailed(String),
+}
+
+impl fmt::Display for SystemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SystemError::Io(path, e) => write!(f, "cannot read {}: {}", path, e),
+            SystemError::Parse(path, content) => write!(f, "unexpected content in {}: {}", path, content),
+            SystemError::NoInvariantTsc => write!(
+                f,
+                "cpu has no constant_tsc flag in {}, cycle based timing is unreliable",
+                CPU_INFO_PATH
+            ),
+            SystemError::CalibrationFailed(reason) => write!(f, "TSC calibration failed: {}", reason),
+        }
+    }
+}
+
+impl Error for SystemError {}
+
+/// how SystemData::detect_with determines the TSC frequency
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CalibrationMode {
+    /// maximum frequency from cpufreq, this is not the TSC rate on many CPUs and missing in most VMs
+    CpuFreq,
+    /// measure the TSC against CLOCK_MONOTONIC_RAW, requires a constant TSC
+    Measure,
+    /// Measure if the TSC is constant, otherwise CpuFreq, falling back to Measure if cpufreq is missing
+    Auto,
+}
 
 #[derive(Clone)]
 pub struct SystemData {
     pub cpu_clock: u64, // base clock for rdtsc in Hz
+    pub topology: CpuTopology,
+}
+
+fn read_file<P: AsRef<Path>>(path: P) -> Result<String, SystemError> {
+    let mut content = String::new();
+    File::open(path.as_ref())
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .map_err(|e| SystemError::Io(path.as_ref().display().to_string(), e))?;
+    Ok(content)
+}
+
+fn read_number<P: AsRef<Path>>(path: P) -> Result<i64, SystemError> {
+    let content = read_file(path.as_ref())?;
+    content
+        .trim()
+        .parse::<i64>()
+        .map_err(|_e| SystemError::Parse(path.as_ref().display().to_string(), content.clone()))
+}
+
+/// parses cpu lists in the kernel format, e.g. "0-3,8,10-11"
+pub fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let first = bounds.next()?.trim().parse::<usize>().ok()?;
+        let last = match bounds.next() {
+            Some(last) => last.trim().parse::<usize>().ok()?,
+            None => first,
+        };
+        cpus.extend(first..last + 1);
+    }
+    Some(cpus)
+}
+
+fn read_cpu_list<P: AsRef<Path>>(path: P) -> Result<Vec<usize>, SystemError> {
+    let content = read_file(path.as_ref())?;
+    parse_cpu_list(&content).ok_or(SystemError::Parse(path.as_ref().display().to_string(), content.clone()))
+}
+
+/// a logical core as seen by the kernel
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuCore {
+    pub id: usize,
+    /// id of the physical core within its package, hyper-threads share it
+    pub core_id: usize,
+    pub package_id: usize,
+    pub numa_node: Option<usize>,
+    /// logical cores on the same physical core, including this one
+    pub thread_siblings: Vec<usize>,
+    /// excluded from the scheduler by isolcpus
+    pub isolated: bool,
+    pub nohz_full: bool,
+}
+
+/// topology of the online logical cores, read from /sys/devices/system/cpu
+#[derive(Debug, Clone, Default)]
+pub struct CpuTopology {
+    pub cores: Vec<CpuCore>,
+    sysfs_root: PathBuf,
+}
+
+/// a pipeline core which is on another NUMA node than the NIC it serves
+#[derive(Debug, Clone, PartialEq)]
+pub struct NumaMismatch {
+    pub pci_address: String,
+    pub pci_node: usize,
+    pub core: usize,
+    pub core_node: usize,
+}
+
+impl fmt::Display for NumaMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "core {} on NUMA node {} serves port {} on NUMA node {}",
+            self.core, self.core_node, self.pci_address, self.pci_node
+        )
+    }
+}
+
+impl CpuTopology {
+    pub fn detect() -> Result<CpuTopology, SystemError> {
+        CpuTopology::from_sysfs(SYSFS_ROOT)
+    }
+
+    /// reads the topology below a sysfs mount point, usually /sys
+    pub fn from_sysfs<P: AsRef<Path>>(sysfs_root: P) -> Result<CpuTopology, SystemError> {
+        let cpu_dir = sysfs_root.as_ref().join("devices/system/cpu");
+        let online = read_cpu_list(cpu_dir.join("online"))?;
+        // these files are missing on older kernels
+        let isolated = read_cpu_list(cpu_dir.join("isolated")).unwrap_or(Vec::new());
+        let nohz_full = read_cpu_list(cpu_dir.join("nohz_full")).unwrap_or(Vec::new());
+        let mut cores = Vec::with_capacity(online.len());
+        for id in online {
+            let dir = cpu_dir.join(format!("cpu{}", id));
+            let topology = dir.join("topology");
+            cores.push(CpuCore {
+                id,
+                core_id: read_number(topology.join("core_id"))? as usize,
+                package_id: read_number(topology.join("physical_package_id"))? as usize,
+                numa_node: CpuTopology::numa_node_of_cpu_dir(&dir),
+                thread_siblings: read_cpu_list(topology.join("thread_siblings_list"))?,
+                isolated: isolated.contains(&id),
+                nohz_full: nohz_full.contains(&id),
+            });
+        }
+        Ok(CpuTopology {
+            cores,
+            sysfs_root: sysfs_root.as_ref().to_path_buf(),
+        })
+    }
+
+    /// the cpu directory contains a link nodeX for its NUMA node
+    fn numa_node_of_cpu_dir(dir: &Path) -> Option<usize> {
+        fs::read_dir(dir)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .find_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                if name.starts_with("node") {
+                    name[4..].parse::<usize>().ok()
+                } else {
+                    None
+                }
+            })
+    }
+
+    #[inline]
+    pub fn core(&self, id: usize) -> Option<&CpuCore> {
+        self.cores.iter().find(|c| c.id == id)
+    }
+
+    pub fn numa_node_of_core(&self, id: usize) -> Option<usize> {
+        self.core(id).and_then(|c| c.numa_node)
+    }
+
+    pub fn numa_nodes(&self) -> Vec<usize> {
+        self.cores
+            .iter()
+            .filter_map(|c| c.numa_node)
+            .collect::<BTreeSet<usize>>()
+            .into_iter()
+            .collect()
+    }
+
+    pub fn isolated_cores(&self) -> Vec<usize> {
+        self.cores.iter().filter(|c| c.isolated).map(|c| c.id).collect()
+    }
+
+    /// true if both logical cores are hyper-threads of the same physical core
+    pub fn share_physical_core(&self, a: usize, b: usize) -> bool {
+        self.core(a).map_or(false, |c| c.thread_siblings.contains(&b))
+    }
+
+    /// NUMA node of a PCI device, e.g. "0000:01:00.0", None if unknown or the system is not NUMA
+    pub fn numa_node_of_pci(&self, pci_address: &str) -> Option<usize> {
+        let path = self
+            .sysfs_root
+            .join("bus/pci/devices")
+            .join(pci_address)
+            .join("numa_node");
+        match read_number(path) {
+            Ok(node) if node >= 0 => Some(node as usize),
+            _ => None,
+        }
+    }
+
+    /// returns and logs the cores which are on another NUMA node than the PCI device
+    pub fn check_numa_locality(&self, pci_address: &str, cores: &[i32]) -> Vec<NumaMismatch> {
+        let pci_node = match self.numa_node_of_pci(pci_address) {
+            Some(node) => node,
+            None => {
+                debug!("NUMA node of {} unknown, skipping NUMA check", pci_address);
+                return Vec::new();
+            }
+        };
+        let mismatches: Vec<NumaMismatch> = cores
+            .iter()
+            .filter_map(|core| {
+                let core_node = self.numa_node_of_core(*core as usize)?;
+                if core_node != pci_node {
+                    Some(NumaMismatch {
+                        pci_address: pci_address.to_string(),
+                        pci_node,
+                        core: *core as usize,
+                        core_node,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect();
+        for mismatch in &mismatches {
+            warn!("{}, this costs performance", mismatch);
+        }
+        mismatches
+    }
+}
+
+/// the flags of the first cpu in /proc/cpuinfo
+fn parse_cpu_flags(cpuinfo: &str) -> Vec<&str> {
+    cpuinfo
+        .lines()
+        .find(|line| line.starts_with("flags"))
+        .and_then(|line| line.splitn(2, ':').nth(1))
+        .map(|flags| flags.split_whitespace().collect())
+        .unwrap_or(Vec::new())
+}
+
+#[inline]
+fn monotonic_raw_nanos() -> Option<u64> {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_RAW, &mut ts) } == 0 {
+        Some(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64)
+    } else {
+        None
+    }
 }
 
 impl SystemData {
-    pub fn detect() -> SystemData {
-        let mut khz = String::new();
-        File::open(CPU_CLOCK_PATH)
-            .and_then(|mut f| f.read_to_string(&mut khz))
-            .expect(&format!("cannot read {}", CPU_CLOCK_PATH));
-        khz.pop(); // remove CR/LF
-        SystemData {
-            cpu_clock: khz.parse::<u64>().unwrap() * 1000,
+    pub fn detect() -> Result<SystemData, SystemError> {
+        SystemData::detect_with(CalibrationMode::Auto)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn detect_with(mode: CalibrationMode) -> Result<SystemData, SystemError> {
+        let cpu_clock = match mode {
+            CalibrationMode::CpuFreq => SystemData::cpufreq_max_frequency()?,
+            CalibrationMode::Measure => {
+                let (constant_tsc, _nonstop_tsc) = SystemData::tsc_flags()?;
+                if !constant_tsc {
+                    return Err(SystemError::NoInvariantTsc);
+                }
+                SystemData::measure_tsc_frequency(
+                    CALIBRATION_ROUNDS,
+                    Duration::from_millis(CALIBRATION_INTERVAL_MILLIS),
+                )?
+            }
+            CalibrationMode::Auto => {
+                let (constant_tsc, nonstop_tsc) = SystemData::tsc_flags().unwrap_or_else(|e| {
+                    warn!("{}, assuming that the TSC is not constant", e);
+                    (false, false)
+                });
+                if constant_tsc {
+                    if !nonstop_tsc {
+                        warn!("TSC is constant but may stop in deep C-states (no nonstop_tsc flag)");
+                    }
+                    SystemData::measure_tsc_frequency(
+                        CALIBRATION_ROUNDS,
+                        Duration::from_millis(CALIBRATION_INTERVAL_MILLIS),
+                    )?
+                } else {
+                    match SystemData::cpufreq_max_frequency() {
+                        Ok(hz) => {
+                            warn!("TSC is not constant, using max cpu frequency {} Hz as TSC rate", hz);
+                            hz
+                        }
+                        Err(e) => {
+                            warn!("{}, TSC is not constant, measuring it nevertheless", e);
+                            SystemData::measure_tsc_frequency(
+                                CALIBRATION_ROUNDS,
+                                Duration::from_millis(CALIBRATION_INTERVAL_MILLIS),
+                            )?
+                        }
+                    }
+                }
+            }
+        };
+        info!("TSC frequency = {} Hz ({:?})", cpu_clock, mode);
+        Ok(SystemData {
+            cpu_clock,
+            topology: SystemData::detect_topology(),
+        })
+    }
+
+    /// without a TSC the TscClock counts nanoseconds
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn detect_with(_mode: CalibrationMode) -> Result<SystemData, SystemError> {
+        Ok(SystemData {
+            cpu_clock: 1_000_000_000,
+            topology: SystemData::detect_topology(),
+        })
+    }
+
+    fn detect_topology() -> CpuTopology {
+        match CpuTopology::detect() {
+            Ok(topology) => topology,
+            Err(e) => {
+                warn!("cannot detect cpu topology: {}", e);
+                CpuTopology::default()
+            }
+        }
+    }
+
+    /// maximum cpu frequency in Hz as reported by cpufreq
+    pub fn cpufreq_max_frequency() -> Result<u64, SystemError> {
+        let khz = read_file(CPU_CLOCK_PATH)?;
+        khz.trim()
+            .parse::<u64>()
+            .map(|khz| khz * 1000)
+            .map_err(|_e| SystemError::Parse(CPU_CLOCK_PATH.to_string(), khz.clone()))
+    }
+
+    /// returns the constant_tsc and nonstop_tsc flags of the cpu
+    pub fn tsc_flags() -> Result<(bool, bool), SystemError> {
+        let cpuinfo = read_file(CPU_INFO_PATH)?;
+        let flags = parse_cpu_flags(&cpuinfo);
+        Ok((flags.contains(&"constant_tsc"), flags.contains(&"nonstop_tsc")))
+    }
+
+    /// measures the TSC against CLOCK_MONOTONIC_RAW in several rounds and returns the median in Hz
+    pub fn measure_tsc_frequency(rounds: usize, interval: Duration) -> Result<u64, SystemError> {
+        if rounds == 0 {
+            return Err(SystemError::CalibrationFailed("no calibration rounds".to_string()));
+        }
+        let clock = TscClock;
+        let mut samples = Vec::with_capacity(rounds);
+        for _i in 0..rounds {
+            let t0 = monotonic_raw_nanos();
+            let c0 = clock.now();
+            thread::sleep(interval);
+            let c1 = clock.now();
+            let t1 = monotonic_raw_nanos();
+            match (t0, t1) {
+                (Some(t0), Some(t1)) if t1 > t0 => {
+                    samples.push(((c1 - c0) as u128 * 1_000_000_000 / (t1 - t0) as u128) as u64)
+                }
+                _ => {
+                    return Err(SystemError::CalibrationFailed(
+                        "CLOCK_MONOTONIC_RAW not available".to_string(),
+                    ))
+                }
+            }
+        }
+        samples.sort();
+        let median = samples[samples.len() / 2];
+        if median < MIN_PLAUSIBLE_TSC_HZ || median > MAX_PLAUSIBLE_TSC_HZ {
+            return Err(SystemError::CalibrationFailed(format!(
+                "implausible frequency {} Hz",
+                median
+            )));
+        }
+        if samples[samples.len() - 1] - samples[0] > median / 100 {
+            warn!("TSC calibration samples differ by more than 1%: {:?}", samples);
+        }
+        Ok(median)
+    }
+
+    /// converts cycles of the time stamp counter into a duration
+    #[inline]
+    pub fn cycles_to_duration(&self, cycles: u64) -> Duration {
+        let secs = cycles / self.cpu_clock;
+        let nanos = (cycles % self.cpu_clock) as u128 * 1_000_000_000 / self.cpu_clock as u128;
+        Duration::new(secs, nanos as u32)
+    }
+
+    /// converts a duration into cycles of the time stamp counter, saturates at u64::MAX
+    #[inline]
+    pub fn duration_to_cycles(&self, duration: &Duration) -> u64 {
+        let cycles = duration.as_secs() as u128 * self.cpu_clock as u128
+            + duration.subsec_nanos() as u128 * self.cpu_clock as u128 / 1_000_000_000;
+        if cycles > u64::max_value() as u128 {
+            u64::max_value()
+        } else {
+            cycles as u64
+        }
+    }
+}
+
+const PROCFS_ROOT: &str = "/proc";
+
+// capability bits, see linux/capability.h
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_IPC_LOCK: u32 = 14;
+const CAP_SYS_ADMIN: u32 = 21;
+
+/// kernel drivers which allow DPDK to drive a NIC, mlx drivers are bifurcated and stay bound to the kernel
+const DPDK_DRIVERS: [&str; 5] = ["vfio-pci", "igb_uio", "uio_pci_generic", "mlx4_core", "mlx5_core"];
+
+/// true for PCI addresses in the domain:bus:device.function format, e.g. "0000:01:00.0"
+pub fn is_pci_address(name: &str) -> bool {
+    let parts: Vec<&str> = name.split(|c| c == ':' || c == '.').collect();
+    parts.len() == 4
+        && parts
+            .iter()
+            .zip(&[4, 2, 2, 1])
+            .all(|(p, len)| p.len() == *len && p.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// hugepages of one size, node is None for the system wide counters
+#[derive(Debug, Clone, PartialEq)]
+pub struct HugepageInfo {
+    pub node: Option<usize>,
+    pub page_size_kb: u64,
+    pub total: u64,
+    pub free: u64,
+}
+
+/// a prerequisite for running DPDK which is not met
+#[derive(Debug, Clone, PartialEq)]
+pub enum PreflightProblem {
+    NoFreeHugepages,
+    NoFreeHugepagesOnNode(usize),
+    HugetlbfsNotMounted,
+    PciDeviceNotFound(String),
+    /// pci address and the driver the device is bound to, if any
+    NoDpdkDriver(String, Option<String>),
+    MissingCapability(&'static str),
+    /// path and reason
+    Unreadable(String, String),
+}
+
+impl fmt::Display for PreflightProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PreflightProblem::NoFreeHugepages => write!(f, "no free hugepages"),
+            PreflightProblem::NoFreeHugepagesOnNode(node) => write!(f, "no free hugepages on NUMA node {}", node),
+            PreflightProblem::HugetlbfsNotMounted => write!(f, "no hugetlbfs mounted"),
+            PreflightProblem::PciDeviceNotFound(pci) => write!(f, "PCI device {} not found", pci),
+            PreflightProblem::NoDpdkDriver(pci, driver) => write!(
+                f,
+                "PCI device {} is bound to {}, expected one of {:?}",
+                pci,
+                driver.as_ref().map_or("no driver", |d| d.as_str()),
+                DPDK_DRIVERS
+            ),
+            PreflightProblem::MissingCapability(cap) => write!(f, "missing capability {}", cap),
+            PreflightProblem::Unreadable(path, reason) => write!(f, "cannot read {}: {}", path, reason),
+        }
+    }
+}
+
+/// result of checking the prerequisites of DPDK before initializing it
+#[derive(Debug, Clone, Default)]
+pub struct PreflightReport {
+    pub hugepages: Vec<HugepageInfo>,
+    pub hugetlbfs_mounts: Vec<String>,
+    pub problems: Vec<PreflightProblem>,
+}
+
+impl PreflightReport {
+    /// checks hugepages, hugetlbfs, drivers of the PCI devices and capabilities of this process,
+    /// missing hugepages on a NUMA node are a problem only if one of the PCI devices or cores is on that node
+    pub fn check(pci_addresses: &[&str], cores: &[i32]) -> PreflightReport {
+        PreflightReport::check_in(SYSFS_ROOT, PROCFS_ROOT, pci_addresses, cores)
+    }
+
+    /// as check, but with sysfs and procfs mounted at the given paths
+    pub fn check_in<P: AsRef<Path>, Q: AsRef<Path>>(
+        sysfs_root: P,
+        procfs_root: Q,
+        pci_addresses: &[&str],
+        cores: &[i32],
+    ) -> PreflightReport {
+        let mut report = PreflightReport::default();
+        let used_nodes = PreflightReport::used_numa_nodes(sysfs_root.as_ref(), pci_addresses, cores);
+        report.check_hugepages(sysfs_root.as_ref(), &used_nodes);
+        report.check_mounts(procfs_root.as_ref());
+        for pci_address in pci_addresses {
+            report.check_driver(sysfs_root.as_ref(), pci_address);
+        }
+        report.check_capabilities(procfs_root.as_ref());
+        report
+    }
+
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// reads the hugepages-<size>kB directories below dir
+    fn read_hugepages(dir: &Path, node: Option<usize>) -> Result<Vec<HugepageInfo>, SystemError> {
+        let entries = fs::read_dir(dir).map_err(|e| SystemError::Io(dir.display().to_string(), e))?;
+        let mut result = Vec::new();
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name().into_string().unwrap_or(String::new());
+            if name.starts_with("hugepages-") && name.ends_with("kB") {
+                let page_size_kb = match name[10..name.len() - 2].parse::<u64>() {
+                    Ok(size) => size,
+                    Err(_) => continue,
+                };
+                result.push(HugepageInfo {
+                    node,
+                    page_size_kb,
+                    total: read_number(entry.path().join("nr_hugepages"))? as u64,
+                    free: read_number(entry.path().join("free_hugepages"))? as u64,
+                });
+            }
+        }
+        result.sort_by_key(|h| h.page_size_kb);
+        Ok(result)
+    }
+
+    /// the NUMA nodes of the PCI devices and cores, as far as they are known
+    fn used_numa_nodes(sysfs_root: &Path, pci_addresses: &[&str], cores: &[i32]) -> BTreeSet<usize> {
+        let pci_nodes = pci_addresses.iter().filter_map(|pci_address| {
+            match read_number(sysfs_root.join("bus/pci/devices").join(pci_address).join("numa_node")) {
+                Ok(node) if node >= 0 => Some(node as usize),
+                _ => None,
+            }
+        });
+        let core_nodes = cores.iter().filter_map(|core| {
+            CpuTopology::numa_node_of_cpu_dir(&sysfs_root.join(format!("devices/system/cpu/cpu{}", core)))
+        });
+        pci_nodes.chain(core_nodes).collect()
+    }
+
+    fn check_hugepages(&mut self, sysfs_root: &Path, used_nodes: &BTreeSet<usize>) {
+        let global = sysfs_root.join("kernel/mm/hugepages");
+        match PreflightReport::read_hugepages(&global, None) {
+            Ok(hugepages) => self.hugepages.extend(hugepages),
+            Err(e) => {
+                self.problems.push(PreflightProblem::Unreadable(
+                    global.display().to_string(),
+                    e.to_string(),
+                ));
+                return;
+            }
+        }
+        if self.hugepages.iter().all(|h| h.free == 0) {
+            self.problems.push(PreflightProblem::NoFreeHugepages);
+            return;
+        }
+        // per NUMA node counters exist only on NUMA kernels
+        let node_dir = sysfs_root.join("devices/system/node");
+        let mut nodes: Vec<usize> = match fs::read_dir(&node_dir) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let name = e.file_name().into_string().ok()?;
+                    if name.starts_with("node") {
+                        name[4..].parse::<usize>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        nodes.sort();
+        for node in nodes {
+            let dir = node_dir.join(format!("node{}/hugepages", node));
+            match PreflightReport::read_hugepages(&dir, Some(node)) {
+                Ok(hugepages) => {
+                    if hugepages.iter().all(|h| h.free == 0) {
+                        if used_nodes.contains(&node) {
+                            self.problems.push(PreflightProblem::NoFreeHugepagesOnNode(node));
+                        } else {
+                            warn!(
+                                "no free hugepages on NUMA node {}, no configured port or core uses it",
+                                node
+                            );
+                        }
+                    }
+                    self.hugepages.extend(hugepages);
+                }
+                Err(e) => debug!("no hugepage counters for node {}: {}", node, e),
+            }
+        }
+    }
+
+    fn check_mounts(&mut self, procfs_root: &Path) {
+        let path = procfs_root.join("mounts");
+        match read_file(&path) {
+            Ok(mounts) => {
+                self.hugetlbfs_mounts = mounts
+                    .lines()
+                    .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+                    .filter(|fields| fields.len() > 2 && fields[2] == "hugetlbfs")
+                    .map(|fields| fields[1].to_string())
+                    .collect();
+                if self.hugetlbfs_mounts.is_empty() {
+                    self.problems.push(PreflightProblem::HugetlbfsNotMounted);
+                }
+            }
+            Err(e) => self
+                .problems
+                .push(PreflightProblem::Unreadable(path.display().to_string(), e.to_string())),
+        }
+    }
+
+    fn check_driver(&mut self, sysfs_root: &Path, pci_address: &str) {
+        let device = sysfs_root.join("bus/pci/devices").join(pci_address);
+        if !device.exists() {
+            self.problems
+                .push(PreflightProblem::PciDeviceNotFound(pci_address.to_string()));
+            return;
+        }
+        let driver = fs::read_link(device.join("driver"))
+            .ok()
+            .and_then(|link| link.file_name().and_then(|n| n.to_str()).map(|n| n.to_string()));
+        match driver {
+            Some(ref d) if DPDK_DRIVERS.contains(&d.as_str()) => (),
+            _ => self
+                .problems
+                .push(PreflightProblem::NoDpdkDriver(pci_address.to_string(), driver)),
+        }
+    }
+
+    fn check_capabilities(&mut self, procfs_root: &Path) {
+        let path = procfs_root.join("self/status");
+        let cap_eff = read_file(&path).ok().and_then(|status| {
+            status
+                .lines()
+                .find(|line| line.starts_with("CapEff:"))
+                .and_then(|line| u64::from_str_radix(line[7..].trim(), 16).ok())
+        });
+        match cap_eff {
+            Some(caps) => {
+                for &(bit, name) in &[
+                    (CAP_NET_ADMIN, "CAP_NET_ADMIN"),
+                    (CAP_IPC_LOCK, "CAP_IPC_LOCK"),
+                    (CAP_SYS_ADMIN, "CAP_SYS_ADMIN"),
+                ] {
+                    if caps & (1u64 << bit) == 0 {
+                        self.problems.push(PreflightProblem::MissingCapability(name));
+                    }
+                }
+            }
+            None => self.problems.push(PreflightProblem::Unreadable(
+                path.display().to_string(),
+                "no CapEff entry".to_string(),
+            )),
         }
     }
 }
@@ -38,3 +707,218 @@ pub fn get_mac_string_from_ifname(ifname: &str) -> Result<String, ParseError> {
         .unwrap();
     Ok(macaddr.lines().next().unwrap_or("").to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn cpu_flags() {
+        let cpuinfo = "processor\t: 0\nvendor_id\t: GenuineIntel\nflags\t\t: fpu vme tsc constant_tsc nonstop_tsc\n\n\
+                       processor\t: 1\nflags\t\t: fpu\n";
+        let flags = parse_cpu_flags(cpuinfo);
+        assert!(flags.contains(&"constant_tsc"));
+        assert!(flags.contains(&"nonstop_tsc"));
+        assert!(!flags.contains(&"flags"));
+        assert!(parse_cpu_flags("processor\t: 0\n").is_empty());
+    }
+
+    #[test]
+    fn measure_tsc() {
+        let hz = SystemData::measure_tsc_frequency(3, Duration::from_millis(10)).unwrap();
+        assert!(hz >= MIN_PLAUSIBLE_TSC_HZ && hz <= MAX_PLAUSIBLE_TSC_HZ);
+        match SystemData::measure_tsc_frequency(0, Duration::from_millis(10)) {
+            Err(SystemError::CalibrationFailed(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn cycle_conversions() {
+        let system_data = SystemData {
+            cpu_clock: 2_400_000_000,
+            topology: CpuTopology::default(),
+        };
+        assert_eq!(system_data.duration_to_cycles(&Duration::from_millis(1)), 2_400_000);
+        assert_eq!(system_data.duration_to_cycles(&Duration::from_secs(3)), 7_200_000_000);
+        assert_eq!(system_data.cycles_to_duration(2_400_000), Duration::from_millis(1));
+        assert_eq!(
+            system_data.cycles_to_duration(3_600_000_000),
+            Duration::from_millis(1500)
+        );
+        let d = Duration::new(12, 345_678_900);
+        assert_eq!(system_data.cycles_to_duration(system_data.duration_to_cycles(&d)), d);
+        assert_eq!(
+            system_data.duration_to_cycles(&Duration::from_secs(u64::max_value())),
+            u64::max_value()
+        );
+    }
+
+    fn write(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    /// two packages with two hyper-threaded cores each, cpu 3 and 7 isolated
+    fn fake_sysfs() -> PathBuf {
+        let root = env::temp_dir().join(format!("netfcts_sysfs_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let cpu_dir = root.join("devices/system/cpu");
+        write(&cpu_dir.join("online"), "0-7\n");
+        write(&cpu_dir.join("isolated"), "3,7\n");
+        write(&cpu_dir.join("nohz_full"), "\n");
+        for cpu in 0..8 {
+            let package = cpu % 4 / 2;
+            let core = cpu % 2;
+            let dir = cpu_dir.join(format!("cpu{}", cpu));
+            write(&dir.join("topology/core_id"), &format!("{}\n", core));
+            write(&dir.join("topology/physical_package_id"), &format!("{}\n", package));
+            write(
+                &dir.join("topology/thread_siblings_list"),
+                &format!("{},{}\n", cpu % 4, cpu % 4 + 4),
+            );
+            symlink(
+                root.join(format!("devices/system/node/node{}", package)),
+                dir.join(format!("node{}", package)),
+            )
+            .unwrap();
+        }
+        write(&root.join("bus/pci/devices/0000:01:00.0/numa_node"), "1\n");
+        write(&root.join("bus/pci/devices/0000:02:00.0/numa_node"), "-1\n");
+        root
+    }
+
+    #[test]
+    fn pci_address() {
+        assert!(is_pci_address("0000:01:00.0"));
+        assert!(is_pci_address("0000:af:1f.7"));
+        assert!(!is_pci_address("01:00.0"));
+        assert!(!is_pci_address("vEth1"));
+    }
+
+    #[test]
+    fn preflight() {
+        let root = env::temp_dir().join(format!("netfcts_preflight_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let sysfs = root.join("sys");
+        let procfs = root.join("proc");
+        for &(dir, total, free) in &[
+            ("kernel/mm/hugepages/hugepages-2048kB", 1024, 512),
+            ("kernel/mm/hugepages/hugepages-1048576kB", 0, 0),
+            ("devices/system/node/node0/hugepages/hugepages-2048kB", 512, 512),
+            ("devices/system/node/node1/hugepages/hugepages-2048kB", 512, 0),
+        ] {
+            write(&sysfs.join(dir).join("nr_hugepages"), &format!("{}\n", total));
+            write(&sysfs.join(dir).join("free_hugepages"), &format!("{}\n", free));
+        }
+        write(&sysfs.join("bus/drivers/vfio-pci/.keep"), "");
+        write(&sysfs.join("bus/drivers/ixgbe/.keep"), "");
+        write(&sysfs.join("bus/pci/devices/0000:01:00.0/numa_node"), "0\n");
+        symlink(
+            sysfs.join("bus/drivers/vfio-pci"),
+            sysfs.join("bus/pci/devices/0000:01:00.0/driver"),
+        )
+        .unwrap();
+        write(&sysfs.join("bus/pci/devices/0000:02:00.0/numa_node"), "1\n");
+        symlink(
+            sysfs.join("bus/drivers/ixgbe"),
+            sysfs.join("bus/pci/devices/0000:02:00.0/driver"),
+        )
+        .unwrap();
+        write(
+            &procfs.join("mounts"),
+            "proc /proc proc rw 0 0\nnodev /mnt/huge hugetlbfs rw,relatime,pagesize=2M 0 0\n",
+        );
+        // CAP_NET_ADMIN and CAP_IPC_LOCK only
+        write(&procfs.join("self/status"), "Name:\ttest\nCapEff:\t0000000000005000\n");
+
+        let report = PreflightReport::check_in(&sysfs, &procfs, &["0000:01:00.0", "0000:02:00.0", "0000:03:00.0"], &[]);
+        assert_eq!(report.hugetlbfs_mounts, vec!["/mnt/huge".to_string()]);
+        assert_eq!(report.hugepages.len(), 4);
+        assert_eq!(
+            report.problems,
+            vec![
+                PreflightProblem::NoFreeHugepagesOnNode(1),
+                PreflightProblem::NoDpdkDriver("0000:02:00.0".to_string(), Some("ixgbe".to_string())),
+                PreflightProblem::PciDeviceNotFound("0000:03:00.0".to_string()),
+                PreflightProblem::MissingCapability("CAP_SYS_ADMIN"),
+            ]
+        );
+        assert!(!report.is_ok());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn preflight_unused_numa_node() {
+        let root = env::temp_dir().join(format!("netfcts_preflight_numa_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let sysfs = root.join("sys");
+        let procfs = root.join("proc");
+        // only node 0 has free hugepages
+        for &(dir, total, free) in &[
+            ("kernel/mm/hugepages/hugepages-2048kB", 512, 512),
+            ("devices/system/node/node0/hugepages/hugepages-2048kB", 512, 512),
+            ("devices/system/node/node1/hugepages/hugepages-2048kB", 0, 0),
+        ] {
+            write(&sysfs.join(dir).join("nr_hugepages"), &format!("{}\n", total));
+            write(&sysfs.join(dir).join("free_hugepages"), &format!("{}\n", free));
+        }
+        for cpu in 0..2 {
+            let dir = sysfs.join(format!("devices/system/cpu/cpu{}", cpu));
+            fs::create_dir_all(&dir).unwrap();
+            symlink(
+                sysfs.join(format!("devices/system/node/node{}", cpu)),
+                dir.join(format!("node{}", cpu)),
+            )
+            .unwrap();
+        }
+        write(&sysfs.join("bus/drivers/vfio-pci/.keep"), "");
+        write(&sysfs.join("bus/pci/devices/0000:01:00.0/numa_node"), "0\n");
+        symlink(
+            sysfs.join("bus/drivers/vfio-pci"),
+            sysfs.join("bus/pci/devices/0000:01:00.0/driver"),
+        )
+        .unwrap();
+        write(&procfs.join("mounts"), "nodev /mnt/huge hugetlbfs rw 0 0\n");
+        write(&procfs.join("self/status"), "CapEff:\t0000003fffffffff\n");
+
+        // port and cores on node 0
+        let report = PreflightReport::check_in(&sysfs, &procfs, &["0000:01:00.0"], &[0]);
+        assert_eq!(report.hugepages.len(), 3);
+        assert!(report.is_ok(), "{:?}", report.problems);
+        // a core on node 1
+        let report = PreflightReport::check_in(&sysfs, &procfs, &["0000:01:00.0"], &[0, 1]);
+        assert_eq!(report.problems, vec![PreflightProblem::NoFreeHugepagesOnNode(1)]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn cpu_list() {
+        assert_eq!(parse_cpu_list("0-3,8,10-11\n"), Some(vec![0, 1, 2, 3, 8, 10, 11]));
+        assert_eq!(parse_cpu_list("\n"), Some(vec![]));
+        assert_eq!(parse_cpu_list("1-x"), None);
+    }
+
+    #[test]
+    fn topology_from_sysfs() {
+        let root = fake_sysfs();
+        let topology = CpuTopology::from_sysfs(&root).unwrap();
+        assert_eq!(topology.cores.len(), 8);
+        assert_eq!(topology.numa_nodes(), vec![0, 1]);
+        assert_eq!(topology.numa_node_of_core(2), Some(1));
+        assert_eq!(topology.isolated_cores(), vec![3, 7]);
+        assert!(topology.share_physical_core(1, 5));
+        assert!(!topology.share_physical_core(1, 2));
+        assert_eq!(topology.numa_node_of_pci("0000:01:00.0"), Some(1));
+        assert_eq!(topology.numa_node_of_pci("0000:02:00.0"), None);
+
+        let mismatches = topology.check_numa_locality("0000:01:00.0", &[1, 2, 3]);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].core, 1);
+        assert_eq!(mismatches[0].core_node, 0);
+        assert!(topology.check_numa_locality("0000:02:00.0", &[1, 2]).is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
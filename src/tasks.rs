@@ -1,4 +1,3 @@
-use std::arch::x86_64::_rdtsc;
 use e2d2::headers::{IpHeader, MacHeader, TcpHeader};
 use e2d2::interface::PmdPort;
 use e2d2::interface::Pdu;
@@ -7,6 +6,7 @@ use e2d2::native::zcsi::{mbuf_alloc_bulk, MBuf};
 use e2d2::queues::MpscProducer;
 use e2d2::scheduler::{Executable, Runnable, Scheduler, StandaloneScheduler};
 use std::sync::Arc;
+use clock::{Clock, TscClock};
 use tcp_common::L234Data;
 use uuid::Uuid;
 //use separator::Separatable;
@@ -27,14 +27,31 @@ pub fn install_task<T: Executable + 'static>(sched: &mut StandaloneScheduler, ta
     uuid
 }
 
-pub struct KniHandleRequest {
+pub struct KniHandleRequest<C: Clock = TscClock> {
     pub kni_port: Arc<PmdPort>,
     pub last_tick: u64,
+    clock: C,
 }
 
-impl Executable for KniHandleRequest {
+impl KniHandleRequest<TscClock> {
+    pub fn new(kni_port: Arc<PmdPort>) -> KniHandleRequest<TscClock> {
+        KniHandleRequest::with_clock(kni_port, TscClock)
+    }
+}
+
+impl<C: Clock> KniHandleRequest<C> {
+    pub fn with_clock(kni_port: Arc<PmdPort>, clock: C) -> KniHandleRequest<C> {
+        KniHandleRequest {
+            kni_port,
+            last_tick: 0,
+            clock,
+        }
+    }
+}
+
+impl<C: Clock> Executable for KniHandleRequest<C> {
     fn execute(&mut self) -> (u32, i32) {
-        let now = unsafe { _rdtsc() };
+        let now = self.clock.now();
         if now - self.last_tick >= 22700 * 1000 {
             // roughly each 10 ms
             unsafe {
@@ -48,7 +65,7 @@ impl Executable for KniHandleRequest {
     }
 }
 
-pub struct PacketInjector<'a> {
+pub struct PacketInjector<'a, C: Clock = TscClock> {
     packet_prototype: Pdu<'a>,
     producer: MpscProducer,
     no_packets: usize,
@@ -58,6 +75,7 @@ pub struct PacketInjector<'a> {
     lastbatch_timestamp: u64,
     start_delay: u64,
     start_time: u64,
+    clock: C,
 }
 
 pub const PRIVATE_ETYPE_PACKET: u16 = 0x08FF;
@@ -75,7 +93,7 @@ pub fn private_etype(etype: &u16) -> bool {
     return *etype == PRIVATE_ETYPE_PACKET || *etype == PRIVATE_ETYPE_TIMER;
 }
 
-impl<'a> PacketInjector<'a> {
+impl<'a> PacketInjector<'a, TscClock> {
     // by setting no_packets=0 batch creation is unlimited
     pub fn new(
         producer: MpscProducer,
@@ -83,7 +101,20 @@ impl<'a> PacketInjector<'a> {
         no_packets: usize,
         min_inter_batch_gap: u64,
         dst_port: u16,
-    ) -> PacketInjector<'a> {
+    ) -> PacketInjector<'a, TscClock> {
+        PacketInjector::with_clock(producer, hd_src_data, no_packets, min_inter_batch_gap, dst_port, TscClock)
+    }
+}
+
+impl<'a, C: Clock> PacketInjector<'a, C> {
+    pub fn with_clock(
+        producer: MpscProducer,
+        hd_src_data: &L234Data,
+        no_packets: usize,
+        min_inter_batch_gap: u64,
+        dst_port: u16,
+        clock: C,
+    ) -> PacketInjector<'a, C> {
         let mut mac = MacHeader::new();
         mac.src = hd_src_data.mac.clone();
         mac.set_etype(PRIVATE_ETYPE_PACKET); // mark this through an unused ethertype as an internal frame, will be re-written later in the pipeline
@@ -114,10 +145,11 @@ impl<'a> PacketInjector<'a> {
             lastbatch_timestamp: 0,
             start_delay: 0,
             start_time: 0,
+            clock,
         }
     }
 
-    pub fn set_start_delay(mut self, delay: u64) -> PacketInjector<'a> {
+    pub fn set_start_delay(mut self, delay: u64) -> PacketInjector<'a, C> {
         self.start_delay = delay;
         self
     }
@@ -129,9 +161,9 @@ impl<'a> PacketInjector<'a> {
     }
 }
 
-impl<'a> Executable for PacketInjector<'a> {
+impl<'a, C: Clock> Executable for PacketInjector<'a, C> {
     fn execute(&mut self) -> (u32, i32) {
-        let now = unsafe { _rdtsc() };
+        let now = self.clock.now();
         if self.start_time == 0 {
             self.start_time = now;
         }
@@ -152,28 +184,40 @@ impl<'a> Executable for PacketInjector<'a> {
             inserted = self.producer.enqueue_mbufs(&mbuf_ptr_array);
             self.sent_packets += inserted;
             assert_eq!(inserted, INJECTOR_BATCH_SIZE);
-            self.lastbatch_timestamp = unsafe { _rdtsc() };
+            self.lastbatch_timestamp = self.clock.now();
         }
         (inserted as u32, self.producer.used_slots() as i32)
     }
 }
 
-pub struct TickGenerator<'a> {
+pub struct TickGenerator<'a, C: Clock = TscClock> {
     packet_prototype: Pdu<'a>,
     producer: MpscProducer,
     last_tick: u64,
     tick_length: u64,
     // in cycles
     tick_count: u64,
+    clock: C,
 }
 
-#[allow(dead_code)]
-impl<'a> TickGenerator<'a> {
+impl<'a> TickGenerator<'a, TscClock> {
     pub fn new(
         producer: MpscProducer,
         hd_src_data: &L234Data,
         tick_length: u64, // in cycles
-    ) -> TickGenerator<'a> {
+    ) -> TickGenerator<'a, TscClock> {
+        TickGenerator::with_clock(producer, hd_src_data, tick_length, TscClock)
+    }
+}
+
+#[allow(dead_code)]
+impl<'a, C: Clock> TickGenerator<'a, C> {
+    pub fn with_clock(
+        producer: MpscProducer,
+        hd_src_data: &L234Data,
+        tick_length: u64, // in cycles
+        clock: C,
+    ) -> TickGenerator<'a, C> {
         let mut mac = MacHeader::new();
         mac.src = hd_src_data.mac.clone();
         mac.set_etype(PRIVATE_ETYPE_TIMER); // mark this through an unused ethertype as an internal frame, will be re-written later in the pipeline
@@ -200,6 +244,7 @@ impl<'a> TickGenerator<'a> {
             last_tick: 0,
             tick_count: 0,
             tick_length,
+            clock,
         }
     }
 
@@ -214,10 +259,10 @@ impl<'a> TickGenerator<'a> {
     }
 }
 
-impl<'a> Executable for TickGenerator<'a> {
+impl<'a, C: Clock> Executable for TickGenerator<'a, C> {
     fn execute(&mut self) -> (u32, i32) {
         let p;
-        let now = unsafe { _rdtsc() };
+        let now = self.clock.now();
         if now - self.last_tick >= self.tick_length {
             unsafe {
                 p = self.packet_prototype.copy();
@@ -1,16 +1,23 @@
 use std::any::Any;
+use std::cmp;
+use std::collections::HashMap;
 use std::convert;
 use std::fmt;
 use std::fmt::Write;
+use std::mem;
 use std::net::SocketAddrV4;
 use std::ops::{Index, IndexMut};
+use std::slice;
 use e2d2::interface::{Pdu, NetSpec};
+use e2d2::headers::{IpHeader, MacHeader, TcpHeader};
 use e2d2::common;
 
 use eui48::MacAddress;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, SerializeMap, Serializer};
 use std::convert::TryFrom;
 
-#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub enum TcpState {
     Listen = 0,
     SynReceived,
@@ -42,7 +49,7 @@ impl convert::From<u8> for TcpState {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum TcpRole {
     Client = 0,
     Server,
@@ -58,7 +65,7 @@ impl convert::From<u8> for TcpRole {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum TcpStatistics {
     SentSyn = 0,
     SentSynAck = 1,
@@ -108,7 +115,7 @@ impl convert::From<usize> for TcpStatistics {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum ReleaseCause {
     Unknown = 0,
     Timeout = 1,
@@ -176,6 +183,33 @@ impl IndexMut<TcpStatistics> for TcpCounter {
     }
 }
 
+/// serialized as map from the name of the TcpStatistics to the count, e.g. {"SentSyn": 10, ...},
+/// missing statistics are deserialized as 0
+impl Serialize for TcpCounter {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(TcpStatistics::Count as usize))?;
+        for i in 0..TcpStatistics::Count as usize {
+            // keys must be strings, e.g. for toml
+            map.serialize_entry(&format!("{:?}", TcpStatistics::from(i)), &self.0[i])?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for TcpCounter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TcpCounter, D::Error> {
+        let map = HashMap::<String, usize>::deserialize(deserializer)?;
+        let mut counter = TcpCounter::new();
+        for (name, count) in map {
+            match (0..TcpStatistics::Count as usize).find(|i| format!("{:?}", TcpStatistics::from(*i)) == name) {
+                Some(i) => counter.0[i] = count,
+                None => return Err(de::Error::custom(format!("unknown TcpStatistics {}", name))),
+            }
+        }
+        Ok(counter)
+    }
+}
+
 impl fmt::Display for TcpCounter {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "Tcp Counters: ",)?;
@@ -244,3 +278,805 @@ pub fn tcp_payload_size(p: &Pdu) -> usize {
     // payload size = ip total length - ip header length -tcp header length
     iph.length() as usize - (iph.ihl() as usize) * 4 - (p.headers().tcp(2).data_offset() as usize) * 4
 }
+
+const TCP_HEADER_LEN: usize = 20;
+const MAX_TCP_OPTIONS_LEN: usize = 40;
+const TCP_FLAG_SYN: u8 = 0x02;
+
+const OPT_EOL: u8 = 0;
+const OPT_NOP: u8 = 1;
+const OPT_MSS: u8 = 2;
+const OPT_WINDOW_SCALE: u8 = 3;
+const OPT_SACK_PERMITTED: u8 = 4;
+const OPT_SACK: u8 = 5;
+const OPT_TIMESTAMPS: u8 = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TcpOptionError {
+    /// the packet is not an IPv4 packet with a TCP segment or its header lengths are inconsistent
+    Malformed(String),
+    /// option of kind with an invalid length field
+    InvalidOption(u8, usize),
+    /// the encoded options exceed the 40 bytes of the TCP header
+    TooLong(usize),
+    /// the buffer of the packet is too short for the new options
+    NoSpace(usize),
+}
+
+impl fmt::Display for TcpOptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TcpOptionError::Malformed(reason) => write!(f, "malformed TCP packet: {}", reason),
+            TcpOptionError::InvalidOption(kind, len) => {
+                write!(f, "TCP option of kind {} with invalid length {}", kind, len)
+            }
+            TcpOptionError::TooLong(len) => write!(f, "TCP options of {} bytes exceed 40 bytes", len),
+            TcpOptionError::NoSpace(missing) => write!(f, "packet buffer lacks {} bytes for TCP options", missing),
+        }
+    }
+}
+
+/// the TCP options of a segment, options of unknown kind are kept as (kind, data)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TcpOptions {
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+    /// (left edge, right edge), at most four blocks fit into the header
+    pub sack_blocks: Vec<(u32, u32)>,
+    /// (TSval, TSecr)
+    pub timestamps: Option<(u32, u32)>,
+    pub unknown: Vec<(u8, Vec<u8>)>,
+}
+
+#[inline]
+fn read_u16(b: &[u8]) -> u16 {
+    (b[0] as u16) << 8 | b[1] as u16
+}
+
+#[inline]
+fn read_u32(b: &[u8]) -> u32 {
+    (read_u16(b) as u32) << 16 | read_u16(&b[2..]) as u32
+}
+
+impl TcpOptions {
+    /// parses the option bytes of a TCP header, i.e. the bytes behind the first 20 bytes
+    pub fn parse(bytes: &[u8]) -> Result<TcpOptions, TcpOptionError> {
+        let mut options = TcpOptions::default();
+        let mut i = 0;
+        while i < bytes.len() {
+            let kind = bytes[i];
+            match kind {
+                OPT_EOL => break,
+                OPT_NOP => {
+                    i += 1;
+                    continue;
+                }
+                _ => (),
+            }
+            if i + 1 >= bytes.len() {
+                return Err(TcpOptionError::InvalidOption(kind, 0));
+            }
+            let len = bytes[i + 1] as usize;
+            if len < 2 || i + len > bytes.len() {
+                return Err(TcpOptionError::InvalidOption(kind, len));
+            }
+            let data = &bytes[i + 2..i + len];
+            let expected = match kind {
+                OPT_MSS => 4,
+                OPT_WINDOW_SCALE => 3,
+                OPT_SACK_PERMITTED => 2,
+                OPT_TIMESTAMPS => 10,
+                OPT_SACK if len >= 10 && (len - 2) % 8 == 0 => len,
+                OPT_SACK => 0,
+                _ => len,
+            };
+            if len != expected {
+                return Err(TcpOptionError::InvalidOption(kind, len));
+            }
+            match kind {
+                OPT_MSS => options.mss = Some(read_u16(data)),
+                OPT_WINDOW_SCALE => options.window_scale = Some(data[0]),
+                OPT_SACK_PERMITTED => options.sack_permitted = true,
+                OPT_TIMESTAMPS => options.timestamps = Some((read_u32(data), read_u32(&data[4..]))),
+                OPT_SACK => {
+                    options.sack_blocks = data
+                        .chunks(8)
+                        .map(|block| (read_u32(block), read_u32(&block[4..])))
+                        .collect()
+                }
+                _ => options.unknown.push((kind, data.to_vec())),
+            }
+            i += len;
+        }
+        Ok(options)
+    }
+
+    /// number of bytes written by write, a multiple of four
+    pub fn encoded_len(&self) -> usize {
+        let mut len = 0;
+        if self.mss.is_some() {
+            len += 4;
+        }
+        if self.timestamps.is_some() {
+            // SACK-permitted or two NOPs in front of the timestamps
+            len += 12;
+        } else if self.sack_permitted {
+            len += 4;
+        }
+        if self.window_scale.is_some() {
+            len += 4;
+        }
+        if !self.sack_blocks.is_empty() {
+            len += 4 + 8 * self.sack_blocks.len();
+        }
+        len += self.unknown.iter().map(|(_, data)| 2 + data.len()).sum::<usize>();
+        (len + 3) & !3
+    }
+
+    /// writes the options in the order used by Linux, padded with EOL to a multiple of four bytes,
+    /// returns the number of bytes written
+    pub fn write(&self, buf: &mut [u8]) -> Result<usize, TcpOptionError> {
+        let len = self.encoded_len();
+        if len > MAX_TCP_OPTIONS_LEN {
+            return Err(TcpOptionError::TooLong(len));
+        }
+        if len > buf.len() {
+            return Err(TcpOptionError::NoSpace(len - buf.len()));
+        }
+        let mut out = Vec::with_capacity(len);
+        if let Some(mss) = self.mss {
+            out.extend_from_slice(&[OPT_MSS, 4]);
+            out.extend_from_slice(&mss.to_be_bytes());
+        }
+        match (self.sack_permitted, self.timestamps) {
+            (sack_permitted, Some((ts_val, ts_ecr))) => {
+                if sack_permitted {
+                    out.extend_from_slice(&[OPT_SACK_PERMITTED, 2]);
+                } else {
+                    out.extend_from_slice(&[OPT_NOP, OPT_NOP]);
+                }
+                out.extend_from_slice(&[OPT_TIMESTAMPS, 10]);
+                out.extend_from_slice(&ts_val.to_be_bytes());
+                out.extend_from_slice(&ts_ecr.to_be_bytes());
+            }
+            (true, None) => out.extend_from_slice(&[OPT_NOP, OPT_NOP, OPT_SACK_PERMITTED, 2]),
+            (false, None) => (),
+        }
+        if let Some(window_scale) = self.window_scale {
+            out.extend_from_slice(&[OPT_NOP, OPT_WINDOW_SCALE, 3, window_scale]);
+        }
+        if !self.sack_blocks.is_empty() {
+            out.extend_from_slice(&[OPT_NOP, OPT_NOP, OPT_SACK, 2 + 8 * self.sack_blocks.len() as u8]);
+            for (left, right) in &self.sack_blocks {
+                out.extend_from_slice(&left.to_be_bytes());
+                out.extend_from_slice(&right.to_be_bytes());
+            }
+        }
+        for (kind, data) in &self.unknown {
+            out.extend_from_slice(&[*kind, 2 + data.len() as u8]);
+            out.extend_from_slice(data);
+        }
+        out.resize(len, OPT_EOL);
+        buf[..len].copy_from_slice(&out);
+        Ok(len)
+    }
+}
+
+/// lengths in bytes of IP header, IP packet and TCP header of an IPv4 packet with a TCP segment
+fn ipv4_tcp_lengths(packet: &[u8]) -> Result<(usize, usize, usize), TcpOptionError> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 {
+        return Err(TcpOptionError::Malformed("no IPv4 header".to_string()));
+    }
+    if packet[9] != 6 {
+        return Err(TcpOptionError::Malformed(format!(
+            "IP protocol {} is not TCP",
+            packet[9]
+        )));
+    }
+    let ihl = (packet[0] & 0x0f) as usize * 4;
+    let ip_len = read_u16(&packet[2..]) as usize;
+    if ihl < 20 || ip_len > packet.len() || ip_len < ihl + TCP_HEADER_LEN {
+        return Err(TcpOptionError::Malformed(format!(
+            "IP header length {}, total length {}, buffer {}",
+            ihl,
+            ip_len,
+            packet.len()
+        )));
+    }
+    let tcp_len = (packet[ihl + 12] >> 4) as usize * 4;
+    if tcp_len < TCP_HEADER_LEN || ihl + tcp_len > ip_len {
+        return Err(TcpOptionError::Malformed(format!("TCP header length {}", tcp_len)));
+    }
+    Ok((ihl, ip_len, tcp_len))
+}
+
+/// parses the TCP options of an IPv4 packet, packet starts with the IP header
+pub fn parse_tcp_options_ipv4(packet: &[u8]) -> Result<TcpOptions, TcpOptionError> {
+    let (ihl, _, tcp_len) = ipv4_tcp_lengths(packet)?;
+    TcpOptions::parse(&packet[ihl + TCP_HEADER_LEN..ihl + tcp_len])
+}
+
+/// the IP total length after replacing the TCP options of the IPv4 packet by options
+pub fn ipv4_length_with_tcp_options(packet: &[u8], options: &TcpOptions) -> Result<usize, TcpOptionError> {
+    let (_, ip_len, tcp_len) = ipv4_tcp_lengths(packet)?;
+    Ok(ip_len - tcp_len + TCP_HEADER_LEN + options.encoded_len())
+}
+
+/// replaces the TCP options of the IPv4 packet in buf, moves the payload and sets TCP data offset and
+/// IP total length, returns the new IP total length. Bytes behind the IP packet in buf are used when the
+/// options grow, bytes which are freed when they shrink are zeroed. The checksums are not updated.
+pub fn write_tcp_options_ipv4(buf: &mut [u8], options: &TcpOptions) -> Result<usize, TcpOptionError> {
+    let (ihl, ip_len, tcp_len) = ipv4_tcp_lengths(buf)?;
+    let options_len = options.encoded_len();
+    if options_len > MAX_TCP_OPTIONS_LEN {
+        return Err(TcpOptionError::TooLong(options_len));
+    }
+    let new_tcp_len = TCP_HEADER_LEN + options_len;
+    let new_ip_len = ip_len - tcp_len + new_tcp_len;
+    if new_ip_len > buf.len() {
+        return Err(TcpOptionError::NoSpace(new_ip_len - buf.len()));
+    }
+    if new_ip_len > u16::max_value() as usize {
+        return Err(TcpOptionError::Malformed(format!("IP total length {}", new_ip_len)));
+    }
+    buf.copy_within(ihl + tcp_len..ip_len, ihl + new_tcp_len);
+    options.write(&mut buf[ihl + TCP_HEADER_LEN..ihl + new_tcp_len])?;
+    if new_ip_len < ip_len {
+        for b in buf[new_ip_len..ip_len].iter_mut() {
+            *b = 0;
+        }
+    }
+    buf[ihl + 12] = (new_tcp_len as u8 / 4) << 4 | (buf[ihl + 12] & 0x0f);
+    buf[2..4].copy_from_slice(&(new_ip_len as u16).to_be_bytes());
+    Ok(new_ip_len)
+}
+
+/// offset of the first option of kind in bytes, bytes must be valid options, see TcpOptions::parse
+fn option_offset(bytes: &[u8], kind: u8) -> Option<usize> {
+    let mut i = 0;
+    while i < bytes.len() && bytes[i] != OPT_EOL {
+        if bytes[i] == kind {
+            return Some(i);
+        }
+        i += if bytes[i] == OPT_NOP { 1 } else { bytes[i + 1] as usize };
+    }
+    None
+}
+
+/// lowers the MSS option of a SYN or SYN-ACK in buf to max_mss or inserts it in front of the other options,
+/// if it is missing. The other options are not touched, an inserted option uses four bytes behind the
+/// IP packet in buf. Segments without SYN flag are not changed, the checksums are not updated.
+/// Returns the new IP total length and whether the packet was changed.
+pub fn clamp_mss_ipv4(buf: &mut [u8], max_mss: u16) -> Result<(usize, bool), TcpOptionError> {
+    let (ihl, ip_len, tcp_len) = ipv4_tcp_lengths(buf)?;
+    if buf[ihl + 13] & TCP_FLAG_SYN == 0 {
+        return Ok((ip_len, false));
+    }
+    let mss = TcpOptions::parse(&buf[ihl + TCP_HEADER_LEN..ihl + tcp_len])?.mss;
+    clamp_parsed_mss_ipv4(buf, mss, max_mss)
+}
+
+/// like clamp_mss_ipv4 for a SYN or SYN-ACK whose options were parsed before and are valid, mss is the value
+/// of its MSS option
+pub fn clamp_parsed_mss_ipv4(buf: &mut [u8], mss: Option<u16>, max_mss: u16) -> Result<(usize, bool), TcpOptionError> {
+    let (ihl, ip_len, tcp_len) = ipv4_tcp_lengths(buf)?;
+    let options_start = ihl + TCP_HEADER_LEN;
+    let options_end = ihl + tcp_len;
+    let new_ip_len = match mss {
+        Some(mss) if mss <= max_mss => return Ok((ip_len, false)),
+        Some(_) => {
+            let mss = match option_offset(&buf[options_start..options_end], OPT_MSS) {
+                Some(offset) => options_start + offset,
+                None => return Err(TcpOptionError::Malformed("no MSS option".to_string())),
+            };
+            buf[mss + 2..mss + 4].copy_from_slice(&max_mss.to_be_bytes());
+            ip_len
+        }
+        None => {
+            if tcp_len + 4 > TCP_HEADER_LEN + MAX_TCP_OPTIONS_LEN {
+                return Err(TcpOptionError::TooLong(tcp_len + 4 - TCP_HEADER_LEN));
+            }
+            if ip_len + 4 > buf.len() {
+                return Err(TcpOptionError::NoSpace(ip_len + 4 - buf.len()));
+            }
+            if ip_len + 4 > u16::max_value() as usize {
+                return Err(TcpOptionError::Malformed(format!("IP total length {}", ip_len + 4)));
+            }
+            buf.copy_within(options_start..ip_len, options_start + 4);
+            buf[options_start..options_start + 2].copy_from_slice(&[OPT_MSS, 4]);
+            buf[options_start + 2..options_start + 4].copy_from_slice(&max_mss.to_be_bytes());
+            buf[ihl + 12] = ((tcp_len + 4) as u8 / 4) << 4 | (buf[ihl + 12] & 0x0f);
+            buf[2..4].copy_from_slice(&(ip_len as u16 + 4).to_be_bytes());
+            ip_len + 4
+        }
+    };
+    Ok((new_ip_len, true))
+}
+
+/// the IPv4 packet behind the MAC header of p, including the bytes behind the IP packet in the mbuf
+#[inline]
+fn ipv4_packet<'a>(p: &'a Pdu) -> &'a [u8] {
+    let len = p.data_len() - mem::size_of::<MacHeader>();
+    unsafe { slice::from_raw_parts(p.headers().ip(1) as *const IpHeader as *const u8, len) }
+}
+
+#[inline]
+fn ipv4_packet_mut<'a>(p: &'a mut Pdu) -> &'a mut [u8] {
+    let len = p.data_len() - mem::size_of::<MacHeader>();
+    unsafe { slice::from_raw_parts_mut(p.headers_mut().ip_mut(1) as *mut IpHeader as *mut u8, len) }
+}
+
+/// one's complement sum of the 16 bit words in data added to sum, without folding
+fn checksum_sum(data: &[u8], mut sum: u64) -> u64 {
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        sum += read_u16(word) as u64;
+    }
+    if let Some(last) = chunks.remainder().first() {
+        sum += (*last as u64) << 8;
+    }
+    sum
+}
+
+fn checksum_fold(mut sum: u64) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// recalculates IP and TCP checksum of the IPv4 packet, packet starts with the IP header. With offload the IP
+/// checksum is zeroed and the TCP checksum is set to the checksum of the pseudo header, which the NIC completes,
+/// like ipv4_phdr_chksum does. Without offload both checksums are calculated.
+pub fn update_checksums_ipv4(packet: &mut [u8], offload: bool) -> Result<(), TcpOptionError> {
+    let (ihl, ip_len, _) = ipv4_tcp_lengths(packet)?;
+    packet[10..12].copy_from_slice(&[0, 0]);
+    let pseudo_header = checksum_sum(&packet[12..20], 6 + (ip_len - ihl) as u64);
+    let tcp_csum = if offload {
+        checksum_fold(pseudo_header)
+    } else {
+        let ip_csum = !checksum_fold(checksum_sum(&packet[..ihl], 0));
+        packet[10..12].copy_from_slice(&ip_csum.to_be_bytes());
+        packet[ihl + 16..ihl + 18].copy_from_slice(&[0, 0]);
+        !checksum_fold(checksum_sum(&packet[ihl..ip_len], pseudo_header))
+    };
+    packet[ihl + 16..ihl + 18].copy_from_slice(&tcp_csum.to_be_bytes());
+    Ok(())
+}
+
+/// recalculates IP and TCP checksum of p, as in prepare_checksum_and_ttl but without changing the TTL, see
+/// update_checksums_ipv4. With tcp_checksum_tx_offload() the header lengths for the offload are set.
+fn update_checksums(p: &mut Pdu) -> Result<(), TcpOptionError> {
+    // rx offload flags may fail the tx offload logic
+    p.clear_rx_offload_flags();
+    let offload = p.tcp_checksum_tx_offload();
+    update_checksums_ipv4(ipv4_packet_mut(p), offload)?;
+    if offload {
+        p.set_l2_len(mem::size_of::<MacHeader>() as u64);
+        p.set_l3_len(mem::size_of::<IpHeader>() as u64);
+        p.set_l4_len(mem::size_of::<TcpHeader>() as u64);
+    }
+    Ok(())
+}
+
+/// applies f to the IPv4 packet in p, after reserving room for at least reserve bytes behind the IP packet.
+/// f returns the new IP total length, the mbuf is trimmed to the new length plus the padding it had before.
+/// If f succeeds, IP and TCP checksum are updated, see update_checksums.
+pub fn update_ipv4_packet<F>(p: &mut Pdu, reserve: usize, f: F) -> Result<usize, TcpOptionError>
+where
+    F: FnOnce(&mut [u8]) -> Result<usize, TcpOptionError>,
+{
+    let (ip_len, available) = {
+        let packet = ipv4_packet(p);
+        (ipv4_tcp_lengths(packet)?.1, packet.len())
+    };
+    let grow_by = (ip_len + reserve).saturating_sub(available);
+    if grow_by > 0 && p.add_to_payload_tail(grow_by).is_err() {
+        return Err(TcpOptionError::NoSpace(grow_by));
+    }
+    let result = f(ipv4_packet_mut(p));
+    let used = match result {
+        Ok(new_ip_len) if new_ip_len < ip_len => available - (ip_len - new_ip_len),
+        Ok(new_ip_len) => cmp::max(new_ip_len, available),
+        Err(_) => available,
+    };
+    if available + grow_by > used {
+        p.trim_payload_size(available + grow_by - used);
+    }
+    let new_ip_len = result?;
+    update_checksums(p)?;
+    Ok(new_ip_len)
+}
+
+/// parses the TCP options of the IPv4 packet in p
+pub fn tcp_options(p: &Pdu) -> Result<TcpOptions, TcpOptionError> {
+    parse_tcp_options_ipv4(ipv4_packet(p))
+}
+
+/// replaces the TCP options of p, see write_tcp_options_ipv4, and updates the checksums, see update_ipv4_packet
+pub fn set_tcp_options(p: &mut Pdu, options: &TcpOptions) -> Result<(), TcpOptionError> {
+    let (ip_len, new_ip_len) = {
+        let packet = ipv4_packet(p);
+        (
+            ipv4_tcp_lengths(packet)?.1,
+            ipv4_length_with_tcp_options(packet, options)?,
+        )
+    };
+    update_ipv4_packet(p, new_ip_len.saturating_sub(ip_len), |packet| {
+        write_tcp_options_ipv4(packet, options)
+    })
+    .map(|_| ())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::Once;
+    use e2d2::config::NetbricksConfiguration;
+    use e2d2::scheduler::initialize_system;
+
+    // MSS 1460, SACK permitted, timestamps, NOP, window scale 7, as sent by Linux
+    const LINUX_SYN_OPTIONS: [u8; 20] = [2, 4, 0x05, 0xb4, 4, 2, 8, 10, 0, 0, 0, 1, 0, 0, 0, 0, 1, 3, 3, 7];
+
+    /// IPv4 packet from 10.0.0.1:49152 to 10.0.0.2:80 with room for extra bytes, checksums are not set
+    fn tcp_packet(flags: u8, options: &[u8], payload: &[u8], room: usize) -> Vec<u8> {
+        let tcp_len = 20 + options.len();
+        let ip_len = 20 + tcp_len + payload.len();
+        let mut p = vec![0u8; ip_len + room];
+        p[..20].copy_from_slice(&[
+            0x45,
+            0,
+            (ip_len >> 8) as u8,
+            ip_len as u8,
+            0,
+            1,
+            0x40,
+            0,
+            64,
+            6,
+            0,
+            0,
+            10,
+            0,
+            0,
+            1,
+            10,
+            0,
+            0,
+            2,
+        ]);
+        p[20..34].copy_from_slice(&[
+            0xc0,
+            0,
+            0,
+            80,
+            0,
+            0,
+            0,
+            100,
+            0,
+            0,
+            0,
+            0,
+            (tcp_len as u8 / 4) << 4,
+            flags,
+        ]);
+        p[34..36].copy_from_slice(&[0xff, 0xff]);
+        p[40..40 + options.len()].copy_from_slice(options);
+        p[40 + options.len()..ip_len].copy_from_slice(payload);
+        p
+    }
+
+    pub fn assert_pdu_checksums_valid(p: &Pdu) {
+        assert_checksums_valid(ipv4_packet(p));
+    }
+
+    /// the one's complement sums including the checksums must be 0xffff
+    fn assert_checksums_valid(p: &[u8]) {
+        let (ihl, ip_len, _) = ipv4_tcp_lengths(p).unwrap();
+        assert_eq!(checksum_fold(checksum_sum(&p[..ihl], 0)), 0xffff);
+        let pseudo_header = checksum_sum(&p[12..20], 6 + (ip_len - ihl) as u64);
+        assert_eq!(checksum_fold(checksum_sum(&p[ihl..ip_len], pseudo_header)), 0xffff);
+    }
+
+    #[test]
+    fn parse_and_write_tcp_options() {
+        let options = TcpOptions::parse(&LINUX_SYN_OPTIONS).unwrap();
+        assert_eq!(
+            options,
+            TcpOptions {
+                mss: Some(1460),
+                window_scale: Some(7),
+                sack_permitted: true,
+                sack_blocks: vec![],
+                timestamps: Some((1, 0)),
+                unknown: vec![],
+            }
+        );
+        let mut buf = [0xffu8; 40];
+        assert_eq!(options.write(&mut buf), Ok(20));
+        assert_eq!(&buf[..20], &LINUX_SYN_OPTIONS[..]);
+
+        let options = TcpOptions {
+            sack_blocks: vec![(1000, 2000), (3000, 4000)],
+            timestamps: Some((7, 8)),
+            unknown: vec![(30, vec![1, 2, 3])],
+            ..Default::default()
+        };
+        // NOP NOP TS, NOP NOP SACK, 30, EOL padding
+        assert_eq!(options.encoded_len(), 12 + 20 + 5 + 3);
+        let len = options.write(&mut buf).unwrap();
+        assert_eq!(&buf[len - 8..], &[30, 5, 1, 2, 3, 0, 0, 0]);
+        assert_eq!(TcpOptions::parse(&buf[..len]), Ok(options.clone()));
+
+        let mut too_many = options.clone();
+        too_many.sack_blocks.push((5000, 6000));
+        assert_eq!(too_many.write(&mut buf), Err(TcpOptionError::TooLong(48)));
+        assert_eq!(options.write(&mut buf[..20]), Err(TcpOptionError::NoSpace(20)));
+
+        // EOL ends the options
+        assert_eq!(TcpOptions::parse(&[1, 3, 3, 2, 0, 2, 4]).unwrap().window_scale, Some(2));
+        assert_eq!(TcpOptions::parse(&[1, 1, 1, 1]), Ok(TcpOptions::default()));
+        assert_eq!(TcpOptions::parse(&[2, 4, 5]), Err(TcpOptionError::InvalidOption(2, 4)));
+        assert_eq!(TcpOptions::parse(&[2, 3, 5]), Err(TcpOptionError::InvalidOption(2, 3)));
+        assert_eq!(
+            TcpOptions::parse(&[5, 6, 0, 0, 0, 0]),
+            Err(TcpOptionError::InvalidOption(5, 6))
+        );
+        assert_eq!(TcpOptions::parse(&[30]), Err(TcpOptionError::InvalidOption(30, 0)));
+    }
+
+    #[test]
+    fn rewrite_tcp_options_in_packet() {
+        let payload = b"GET / HTTP/1.1";
+        let mut p = tcp_packet(0x18, &LINUX_SYN_OPTIONS, payload, 12);
+        let mut options = parse_tcp_options_ipv4(&p).unwrap();
+        assert_eq!(options.mss, Some(1460));
+
+        // shrink to MSS and window scale
+        options.sack_permitted = false;
+        options.timestamps = None;
+        let old_len = p.len();
+        assert_eq!(ipv4_length_with_tcp_options(&p, &options), Ok(40 + 8 + payload.len()));
+        let new_len = write_tcp_options_ipv4(&mut p, &options).unwrap();
+        assert_eq!(new_len, 40 + 8 + payload.len());
+        assert_eq!(read_u16(&p[2..]) as usize, new_len);
+        assert_eq!(p[32] >> 4, 7);
+        assert_eq!(&p[48..new_len], &payload[..]);
+        assert!(p[new_len..old_len - 12].iter().all(|b| *b == 0));
+        assert_eq!(parse_tcp_options_ipv4(&p), Ok(options.clone()));
+
+        // grow again into the room behind the packet
+        options.timestamps = Some((5, 6));
+        options.sack_permitted = true;
+        options.sack_blocks = vec![(1, 2)];
+        assert_eq!(options.encoded_len(), 4 + 12 + 4 + 12);
+        let new_len = write_tcp_options_ipv4(&mut p, &options).unwrap();
+        assert_eq!(new_len, 40 + 32 + payload.len());
+        assert_eq!(&p[72..new_len], &payload[..]);
+        assert_eq!(parse_tcp_options_ipv4(&p), Ok(options.clone()));
+
+        options.unknown.push((30, vec![0; 6]));
+        assert_eq!(
+            write_tcp_options_ipv4(&mut p, &options),
+            Err(TcpOptionError::NoSpace(8))
+        );
+
+        // a SYN without options gets an MSS option
+        let mut p = tcp_packet(0x02, &[], &[], 4);
+        let options = TcpOptions {
+            mss: Some(1200),
+            ..Default::default()
+        };
+        assert_eq!(write_tcp_options_ipv4(&mut p, &options), Ok(44));
+        assert_eq!(read_u16(&p[2..]), 44);
+        assert_eq!(p[32] >> 4, 6);
+        assert_eq!(&p[40..44], &[2, 4, 0x04, 0xb0]);
+
+        let mut udp = tcp_packet(0x02, &[], &[], 0);
+        udp[9] = 17;
+        assert!(parse_tcp_options_ipv4(&udp).is_err());
+    }
+
+    #[test]
+    fn clamp_mss_in_packet() {
+        // SYN with MSS 1460 is lowered in place
+        let mut p = tcp_packet(0x02, &LINUX_SYN_OPTIONS, &[], 0);
+        assert_eq!(clamp_mss_ipv4(&mut p, 1400), Ok((60, true)));
+        assert_eq!(&p[40..44], &[2, 4, 0x05, 0x78]);
+        assert_eq!(&p[44..60], &LINUX_SYN_OPTIONS[4..]);
+
+        // a smaller MSS and segments without SYN are not changed
+        let unchanged = p.clone();
+        assert_eq!(clamp_mss_ipv4(&mut p, 1460), Ok((60, false)));
+        assert_eq!(p, unchanged);
+        let mut p = tcp_packet(0x10, &LINUX_SYN_OPTIONS, &[], 0);
+        let unchanged = p.clone();
+        assert_eq!(clamp_mss_ipv4(&mut p, 536), Ok((60, false)));
+        assert_eq!(p, unchanged);
+
+        // SYN-ACK without MSS gets the option in front of window scale
+        let mut p = tcp_packet(0x12, &[1, 3, 3, 7], &[], 4);
+        assert_eq!(clamp_mss_ipv4(&mut p, 1360), Ok((48, true)));
+        assert_eq!(read_u16(&p[2..]), 48);
+        assert_eq!(p[32] >> 4, 7);
+        assert_eq!(&p[40..48], &[2, 4, 0x05, 0x50, 1, 3, 3, 7]);
+        assert_eq!(parse_tcp_options_ipv4(&p).unwrap().mss, Some(1360));
+
+        let mut p = tcp_packet(0x02, &[], &[], 2);
+        let unchanged = p.clone();
+        assert_eq!(clamp_mss_ipv4(&mut p, 1360), Err(TcpOptionError::NoSpace(2)));
+        assert_eq!(p, unchanged);
+        let mut p = tcp_packet(0x02, &[1; 40], &[], 4);
+        assert_eq!(clamp_mss_ipv4(&mut p, 1360), Err(TcpOptionError::TooLong(44)));
+
+        // options parsed before are not parsed again
+        let mut p = tcp_packet(0x02, &LINUX_SYN_OPTIONS, &[], 0);
+        assert_eq!(clamp_parsed_mss_ipv4(&mut p, Some(1460), 1200), Ok((60, true)));
+        assert_eq!(&p[40..44], &[2, 4, 0x04, 0xb0]);
+        let mut p = tcp_packet(0x02, &[1, 3, 3, 7], &[], 0);
+        assert_eq!(
+            clamp_parsed_mss_ipv4(&mut p, Some(1460), 1200),
+            Err(TcpOptionError::Malformed("no MSS option".to_string()))
+        );
+    }
+
+    #[test]
+    fn update_checksums_of_ipv4_packet() {
+        let mut p = tcp_packet(0x12, &LINUX_SYN_OPTIONS, b"payload", 3);
+        p[10..12].copy_from_slice(&[0x12, 0x34]);
+        update_checksums_ipv4(&mut p, false).unwrap();
+        assert_checksums_valid(&p);
+        // odd payload length, the bytes behind the IP packet are not summed up
+        let mut p = tcp_packet(0x10, &[], b"odd", 3);
+        p[43..].copy_from_slice(&[0xff; 3]);
+        update_checksums_ipv4(&mut p, false).unwrap();
+        assert_checksums_valid(&p);
+
+        // with offload the NIC completes the TCP checksum, starting from the checksum of the pseudo header
+        let mut p = tcp_packet(0x12, &LINUX_SYN_OPTIONS, b"payload", 3);
+        p[10..12].copy_from_slice(&[0x12, 0x34]);
+        update_checksums_ipv4(&mut p, true).unwrap();
+        assert_eq!(&p[10..12], &[0, 0]);
+        let pseudo_header = checksum_fold(checksum_sum(&p[12..20], 6 + 20 + 20 + 7));
+        assert_eq!(read_u16(&p[36..38]), pseudo_header);
+        let mut offloaded = p.clone();
+        update_checksums_ipv4(&mut offloaded, false).unwrap();
+        assert_checksums_valid(&offloaded);
+
+        let mut p = tcp_packet(0x12, &[], &[], 0);
+        p[9] = 17;
+        assert_eq!(
+            update_checksums_ipv4(&mut p, false),
+            Err(TcpOptionError::Malformed("IP protocol 17 is not TCP".to_string()))
+        );
+    }
+
+    /// the steps of clamp_mss on a hand-built SYN, with and without checksum offload
+    #[test]
+    fn clamp_mss_with_checksums() {
+        for offload in &[false, true] {
+            let mut p = tcp_packet(0x02, &[], b"data", 4);
+            assert_eq!(clamp_mss_ipv4(&mut p, 1300), Ok((48, true)));
+            update_checksums_ipv4(&mut p, *offload).unwrap();
+            assert_eq!(parse_tcp_options_ipv4(&p).unwrap().mss, Some(1300));
+            assert_eq!(&p[44..48], b"data");
+            if *offload {
+                assert_eq!(&p[10..12], &[0, 0]);
+                let pseudo_header = checksum_fold(checksum_sum(&p[12..20], 6 + 28));
+                assert_eq!(read_u16(&p[36..38]), pseudo_header);
+            } else {
+                assert_checksums_valid(&p);
+            }
+        }
+    }
+
+    /// Pdus are allocated from the mempools of DPDK, the tests with Pdus need hugepages and root privileges,
+    /// run them with: cargo test -- --ignored
+    fn init_dpdk() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            initialize_system(&NetbricksConfiguration::new()).unwrap();
+        });
+    }
+
+    /// SYN from 10.0.0.1:49152 to 10.0.0.2:80 without options
+    pub fn syn_pdu(payload: &[u8]) -> Pdu {
+        init_dpdk();
+        let mac = MacHeader::new();
+        let mut ip = IpHeader::new();
+        ip.set_src(0x0a00_0001);
+        ip.set_dst(0x0a00_0002);
+        ip.set_ttl(64);
+        ip.set_version(4);
+        ip.set_protocol(6);
+        ip.set_ihl(5);
+        ip.set_length(40 + payload.len() as u16);
+        let mut tcp = TcpHeader::new();
+        tcp.set_syn_flag();
+        tcp.set_src_port(49152);
+        tcp.set_dst_port(80);
+        tcp.set_data_offset(5);
+        let mut p = Pdu::new_pdu().unwrap();
+        p.push_header(&mac);
+        p.push_header(&ip);
+        p.push_header(&tcp);
+        if !payload.is_empty() {
+            p.add_to_payload_tail(payload.len()).unwrap();
+            ipv4_packet_mut(&mut p)[40..].copy_from_slice(payload);
+        }
+        p
+    }
+
+    #[test]
+    #[ignore]
+    fn set_tcp_options_of_pdu() {
+        let payload = b"GET / HTTP/1.1";
+        let mut p = syn_pdu(payload);
+        assert_eq!(tcp_options(&p), Ok(TcpOptions::default()));
+
+        // grow, the mbuf is extended
+        let options = TcpOptions::parse(&LINUX_SYN_OPTIONS).unwrap();
+        set_tcp_options(&mut p, &options).unwrap();
+        assert_eq!(p.data_len(), 14 + 60 + payload.len());
+        assert_eq!(tcp_options(&p), Ok(options));
+        assert_eq!(tcp_payload_size(&p), payload.len());
+        assert_eq!(&ipv4_packet(&p)[60..], &payload[..]);
+        assert_pdu_checksums_valid(&p);
+
+        // shrink, the mbuf is trimmed
+        let options = TcpOptions {
+            mss: Some(1200),
+            ..Default::default()
+        };
+        set_tcp_options(&mut p, &options).unwrap();
+        assert_eq!(p.data_len(), 14 + 44 + payload.len());
+        assert_eq!(tcp_options(&p), Ok(options.clone()));
+        assert_eq!(&ipv4_packet(&p)[44..], &payload[..]);
+        assert_pdu_checksums_valid(&p);
+
+        // options which do not fit into the TCP header leave p unchanged
+        let unchanged = ipv4_packet(&p).to_vec();
+        let too_long = TcpOptions {
+            sack_blocks: vec![(1, 2); 5],
+            ..options
+        };
+        assert_eq!(set_tcp_options(&mut p, &too_long), Err(TcpOptionError::TooLong(48)));
+        assert_eq!(ipv4_packet(&p), &unchanged[..]);
+    }
+
+    #[test]
+    #[ignore]
+    fn update_ipv4_packet_of_pdu() {
+        let mut p = syn_pdu(&[]);
+        // padding behind the IP packet is used before the mbuf is extended
+        p.add_to_payload_tail(6).unwrap();
+        let ip_len = update_ipv4_packet(&mut p, 8, |packet| {
+            assert_eq!(packet.len(), 48);
+            packet[40..48].copy_from_slice(b"payload!");
+            packet[2..4].copy_from_slice(&48u16.to_be_bytes());
+            Ok(48)
+        });
+        assert_eq!(ip_len, Ok(48));
+        assert_eq!(p.data_len(), 14 + 48);
+        assert_eq!(tcp_payload_size(&p), 8);
+        assert_pdu_checksums_valid(&p);
+
+        // on errors the mbuf keeps its length
+        let unchanged = ipv4_packet(&p).to_vec();
+        let error = TcpOptionError::Malformed("test".to_string());
+        assert_eq!(update_ipv4_packet(&mut p, 100, |_| Err(error.clone())), Err(error));
+        assert_eq!(ipv4_packet(&p), &unchanged[..]);
+
+        // shrink, the mbuf is trimmed
+        let ip_len = update_ipv4_packet(&mut p, 0, |packet| {
+            packet[2..4].copy_from_slice(&40u16.to_be_bytes());
+            Ok(40)
+        });
+        assert_eq!(ip_len, Ok(40));
+        assert_eq!(p.data_len(), 14 + 40);
+        assert_eq!(tcp_payload_size(&p), 0);
+        assert_pdu_checksums_valid(&p);
+    }
+}
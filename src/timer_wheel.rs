@@ -1,39 +1,157 @@
-use std::arch::x86_64::_rdtsc;
 use std::clone::Clone;
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::fmt::Debug;
+use std::time::Duration;
 use std::vec::Drain;
+use clock::{Clock, TscClock};
+use system::SystemData;
 //use separator::Separatable;
 
-/*
-pub fn duration_to_millis(dur: &Duration) -> u64 {
-    dur.as_secs() * 1000 + (dur.subsec_nanos() / 1000000) as u64
+/// slot capacity used when a wheel is created with_resolution
+pub const DEFAULT_SLOT_CAPACITY: usize = 64;
+
+/// opaque reference to a scheduled event, returned by schedule and used for cancel and reschedule;
+/// the generation makes sure that a handle never refers to an event which has fired or has been cancelled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerHandle {
+    id: u32,
+    generation: u32,
+}
+
+/// maps handles to the current location (slot, index) of their events in the wheel
+struct HandleTable {
+    entries: Vec<(u32, (u32, u32))>, // generation and location
+    free_ids: Vec<u32>,
+}
+
+impl HandleTable {
+    fn new() -> HandleTable {
+        HandleTable {
+            entries: Vec::new(),
+            free_ids: Vec::new(),
+        }
+    }
+
+    #[inline]
+    fn allocate(&mut self, location: (u32, u32)) -> TimerHandle {
+        match self.free_ids.pop() {
+            Some(id) => {
+                self.entries[id as usize].1 = location;
+                TimerHandle {
+                    id,
+                    generation: self.entries[id as usize].0,
+                }
+            }
+            None => {
+                self.entries.push((0, location));
+                TimerHandle {
+                    id: (self.entries.len() - 1) as u32,
+                    generation: 0,
+                }
+            }
+        }
+    }
+
+    /// location of the event, None if the handle is stale
+    #[inline]
+    fn location(&self, handle: &TimerHandle) -> Option<(u32, u32)> {
+        match self.entries.get(handle.id as usize) {
+            Some(&(generation, location)) if generation == handle.generation => Some(location),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    fn relocate(&mut self, id: u32, location: (u32, u32)) {
+        self.entries[id as usize].1 = location;
+    }
+
+    /// invalidates all handles with this id
+    #[inline]
+    fn release(&mut self, id: u32) {
+        let entry = &mut self.entries[id as usize];
+        entry.0 = entry.0.wrapping_add(1);
+        self.free_ids.push(id);
+    }
+}
+
+/// an event in a slot, what is None when the event has been cancelled
+#[derive(Clone)]
+struct Entry<T> {
+    id: u32,
+    what: Option<T>,
+}
+
+/// iterator over the expired events of a slot, skips cancelled events
+pub struct Expired<'a, T: 'a> {
+    drain: Drain<'a, Entry<T>>,
 }
 
-pub fn duration_to_micros(dur: &Duration) -> u64 {
-    dur.as_secs() * 1000 * 1000 + (dur.subsec_nanos() / 1000) as u64
+impl<'a, T> Iterator for Expired<'a, T> {
+    type Item = T;
+
+    #[inline]
+    fn next(&mut self) -> Option<T> {
+        while let Some(entry) = self.drain.next() {
+            if entry.what.is_some() {
+                return entry.what;
+            }
+        }
+        None
+    }
 }
 
-*/
+/// releases the handles of the pending events in slot and returns the iterator draining it
+#[inline]
+fn expire<'a, T>(handles: &mut HandleTable, slot: &'a mut Vec<Entry<T>>) -> Expired<'a, T> {
+    for entry in slot.iter().filter(|e| e.what.is_some()) {
+        handles.release(entry.id);
+    }
+    Expired { drain: slot.drain(..) }
+}
 
-pub struct TimerWheel<T>
+/// A timer wheel with no_slots slots of resolution_cycles each, timeouts are limited to get_max_timeout_cycles,
+/// HierarchicalTimerWheel replaces it for longer timeouts.
+pub struct TimerWheel<T, C = TscClock>
 where
     T: Clone,
+    C: Clock,
 {
     resolution_cycles: u64,
     no_slots: usize,
     last_slot: usize,  // slot which was drained at the last tick
     last_advance: u64, // number of slots drained since start
     start: u64,        // time when wheel started
-    slots: Vec<Vec<T>>,
+    slots: Vec<Vec<Entry<T>>>,
+    handles: HandleTable,
+    clock: C,
+}
+
+impl<T> TimerWheel<T, TscClock>
+where
+    T: Clone,
+{
+    pub fn new(no_slots: usize, resolution_cycles: u64, slot_capacity: usize) -> TimerWheel<T, TscClock> {
+        TimerWheel::with_clock(no_slots, resolution_cycles, slot_capacity, TscClock)
+    }
+
+    /// creates a wheel whose slots span the given resolution
+    pub fn with_resolution(resolution: Duration, no_slots: usize, system_data: &SystemData) -> TimerWheel<T, TscClock> {
+        TimerWheel::with_resolution_and_clock(resolution, no_slots, system_data, TscClock)
+    }
 }
 
 #[allow(dead_code)]
-impl<T> TimerWheel<T>
+impl<T, C> TimerWheel<T, C>
 where
     T: Clone,
+    C: Clock,
 {
-    pub fn new(no_slots: usize, resolution_cycles: u64, slot_capacity: usize) -> TimerWheel<T> {
+    pub fn with_clock(no_slots: usize, resolution_cycles: u64, slot_capacity: usize, clock: C) -> TimerWheel<T, C> {
+        assert!(
+            resolution_cycles > 0,
+            "resolution of timer wheel must be at least one cycle"
+        );
         //let now = utils::rdtsc_unsafe();
         //println!("wheel start = {:?}", now);
         TimerWheel {
@@ -43,20 +161,42 @@ where
             last_advance: 0,
             start: 0,
             slots: vec![Vec::with_capacity(slot_capacity); no_slots],
+            handles: HandleTable::new(),
+            clock,
         }
     }
 
+    /// creates a wheel whose slots span the given resolution, a resolution below one cycle is raised to one cycle
+    pub fn with_resolution_and_clock(
+        resolution: Duration,
+        no_slots: usize,
+        system_data: &SystemData,
+        clock: C,
+    ) -> TimerWheel<T, C> {
+        TimerWheel::with_clock(
+            no_slots,
+            max(1, system_data.duration_to_cycles(&resolution)),
+            DEFAULT_SLOT_CAPACITY,
+            clock,
+        )
+    }
+
     #[inline]
     pub fn resolution(&self) -> u64 {
         self.resolution_cycles
     }
 
+    #[inline]
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
     #[inline]
     pub fn get_max_timeout_cycles(&self) -> u64 {
         (self.no_slots as u64 - 1) * self.resolution_cycles as u64
     }
 
-    pub fn tick(&mut self, now: &u64) -> (Option<Drain<T>>, bool) {
+    pub fn tick(&mut self, now: &u64) -> (Option<Expired<T>>, bool) {
         if self.start != 0 {
             // only when the wheel has been started
             let dur = *now - self.start;
@@ -78,7 +218,10 @@ where
                         self.last_slot,
                         self.slots[self.last_slot].len()
                     );
-                    return (Some(self.slots[self.last_slot].drain(..)), slots_to_process > 1);
+                    return (
+                        Some(expire(&mut self.handles, &mut self.slots[self.last_slot])),
+                        slots_to_process > 1,
+                    );
                 } else {
                     slots_to_process -= 1
                 }
@@ -87,59 +230,382 @@ where
         (None, false)
     }
 
-    /// schedules a new element and returns the slot and the index in the wheel
-    pub fn schedule(&mut self, after_cycles: &u64, what: T) -> (u16, u16)
+    /// schedules a new element and returns the handle for cancelling or rescheduling it,
+    /// after_cycles beyond get_max_timeout_cycles are clamped, so that the event fires too early but does
+    /// not wrap around, use a HierarchicalTimerWheel for longer timeouts
+    pub fn schedule(&mut self, after_cycles: &u64, what: T) -> TimerHandle
     where
         T: Debug,
     {
-        let now = unsafe { _rdtsc() };
+        let now = self.clock.now();
         //initialize start time
         if self.start == 0 {
             self.start = now - self.resolution_cycles;
         }
-        let dur = *after_cycles + now - self.start;
+        let max_timeout_cycles = self.get_max_timeout_cycles();
+        if *after_cycles > max_timeout_cycles {
+            warn!(
+                "timeout of {} cycles exceeds the maximum of {} cycles of the timer wheel, use HierarchicalTimerWheel",
+                after_cycles, max_timeout_cycles
+            );
+        }
+        let dur = min(*after_cycles, max_timeout_cycles) + now - self.start;
         let slots = dur / self.resolution_cycles - 1;
-        let slot = slots.wrapping_rem(self.no_slots as u64);
+        let slot = slots.wrapping_rem(self.no_slots as u64) as usize;
         debug!(
             "scheduling port {:?} at {:?} in slot {}",
             what,
-            self.slots[slot as usize].len(),
+            self.slots[slot].len(),
             slot
         );
-        self.slots[slot as usize].push(what);
-        (slot as u16, (self.slots[slot as usize].len() - 1) as u16)
+        let handle = self.handles.allocate((slot as u32, self.slots[slot].len() as u32));
+        self.slots[slot].push(Entry {
+            id: handle.id,
+            what: Some(what),
+        });
+        handle
     }
 
-    // we use replace to remove elements from the wheel by overwriting them with an invalid value
+    /// schedules a new element after a duration, system_data converts it into cycles
+    pub fn schedule_after(&mut self, after: Duration, what: T, system_data: &SystemData) -> TimerHandle
+    where
+        T: Debug,
+    {
+        self.schedule(&system_data.duration_to_cycles(&after), what)
+    }
+
+    /// removes the event from the wheel, returns None if it has already fired or has been cancelled
+    pub fn cancel(&mut self, handle: &TimerHandle) -> Option<T> {
+        let (slot, index) = self.handles.location(handle)?;
+        let old = self.slots[slot as usize][index as usize].what.take();
+        self.handles.release(handle.id);
+        old
+    }
+
+    /// cancels the event and schedules it again after_cycles from now,
+    /// returns None and leaves the wheel untouched if the handle is stale
+    pub fn reschedule(&mut self, handle: &TimerHandle, after_cycles: &u64) -> Option<TimerHandle>
+    where
+        T: Debug,
+    {
+        let what = self.cancel(handle)?;
+        Some(self.schedule(after_cycles, what))
+    }
+
+    /// replaces the element of a pending event, returns None if the handle is stale
     #[inline]
-    pub fn replace(&mut self, slot_and_index: (u16, u16), new_element: T) -> Option<T> {
-        let slot = slot_and_index.0 as usize;
-        let index = slot_and_index.1 as usize;
+    pub fn replace(&mut self, handle: &TimerHandle, new_element: T) -> Option<T> {
+        let (slot, index) = self.handles.location(handle)?;
         debug!("replace: slot = {}, index = {}", slot, index);
-        if index < self.slots[slot].len() {
-            let old = self.slots[slot][index].clone();
-            self.slots[slot][index] = new_element;
-            Some(old)
+        self.slots[slot as usize][index as usize].what.replace(new_element)
+    }
+}
+
+/// A hierarchical (cascading) timer wheel. Level 0 has the same granularity as TimerWheel,
+/// each further level covers no_slots times the span of the level below. Events which are
+/// too far in the future for level 0 are parked on an upper level and cascade down as the
+/// wheel advances, so timeouts are not limited by no_slots * resolution.
+/// Levels are added on demand, level 0 is drained exactly like the slots of TimerWheel.
+pub struct HierarchicalTimerWheel<T, C = TscClock>
+where
+    T: Clone,
+    C: Clock,
+{
+    resolution_cycles: u64,
+    no_slots: usize,
+    current: u64, // number of level 0 slots drained since start
+    start: u64,   // time when wheel started
+    slots: Vec<Vec<Entry<T>>>,
+    // upper levels, index 0 is level 1; entries carry their expiry (in units of resolution_cycles)
+    upper_levels: Vec<Vec<Vec<(u64, Entry<T>)>>>,
+    // number of events per level, index 0 is level 0
+    counts: Vec<usize>,
+    // slots of upper levels are numbered from level * no_slots on
+    handles: HandleTable,
+    clock: C,
+}
+
+impl<T> HierarchicalTimerWheel<T, TscClock>
+where
+    T: Clone,
+{
+    pub fn new(no_slots: usize, resolution_cycles: u64, slot_capacity: usize) -> HierarchicalTimerWheel<T, TscClock> {
+        HierarchicalTimerWheel::with_clock(no_slots, resolution_cycles, slot_capacity, TscClock)
+    }
+
+    /// creates a wheel whose level 0 slots span the given resolution
+    pub fn with_resolution(
+        resolution: Duration,
+        no_slots: usize,
+        system_data: &SystemData,
+    ) -> HierarchicalTimerWheel<T, TscClock> {
+        HierarchicalTimerWheel::with_resolution_and_clock(resolution, no_slots, system_data, TscClock)
+    }
+}
+
+#[allow(dead_code)]
+impl<T, C> HierarchicalTimerWheel<T, C>
+where
+    T: Clone,
+    C: Clock,
+{
+    pub fn with_clock(
+        no_slots: usize,
+        resolution_cycles: u64,
+        slot_capacity: usize,
+        clock: C,
+    ) -> HierarchicalTimerWheel<T, C> {
+        assert!(no_slots >= 2);
+        assert!(
+            resolution_cycles > 0,
+            "resolution of timer wheel must be at least one cycle"
+        );
+        HierarchicalTimerWheel {
+            resolution_cycles,
+            no_slots,
+            current: 0,
+            start: 0,
+            slots: vec![Vec::with_capacity(slot_capacity); no_slots],
+            upper_levels: Vec::new(),
+            counts: vec![0],
+            handles: HandleTable::new(),
+            clock,
+        }
+    }
+
+    /// creates a wheel whose level 0 slots span the given resolution, a resolution below one cycle is raised
+    /// to one cycle
+    pub fn with_resolution_and_clock(
+        resolution: Duration,
+        no_slots: usize,
+        system_data: &SystemData,
+        clock: C,
+    ) -> HierarchicalTimerWheel<T, C> {
+        HierarchicalTimerWheel::with_clock(
+            no_slots,
+            max(1, system_data.duration_to_cycles(&resolution)),
+            DEFAULT_SLOT_CAPACITY,
+            clock,
+        )
+    }
+
+    #[inline]
+    pub fn resolution(&self) -> u64 {
+        self.resolution_cycles
+    }
+
+    #[inline]
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// there is no upper limit for timeouts in a hierarchical wheel
+    #[inline]
+    pub fn get_max_timeout_cycles(&self) -> u64 {
+        u64::max_value()
+    }
+
+    /// current number of levels, including level 0
+    #[inline]
+    pub fn levels(&self) -> usize {
+        self.upper_levels.len() + 1
+    }
+
+    /// number of slots covered by one slot of the given level, None if this exceeds u64
+    #[inline]
+    fn span(&self, level: usize) -> Option<u64> {
+        (self.no_slots as u64).checked_pow(level as u32)
+    }
+
+    /// puts an event which expires when the wheel has drained expiry slots on the appropriate level
+    /// and returns its location
+    fn insert(&mut self, expiry: u64, entry: Entry<T>) -> (u32, u32) {
+        let n = self.no_slots as u64;
+        let expiry = if expiry <= self.current {
+            self.current + 1
+        } else {
+            expiry
+        };
+        if expiry - 1 - self.current < n {
+            let slot = ((expiry - 1) % n) as usize;
+            self.slots[slot].push(entry);
+            self.counts[0] += 1;
+            return (slot as u32, (self.slots[slot].len() - 1) as u32);
+        }
+        let mut level = 1;
+        loop {
+            let fits = match self.span(level) {
+                Some(span) => (expiry - 1) / span - self.current / span < n,
+                None => true,
+            };
+            if fits {
+                while level > self.upper_levels.len() {
+                    debug!("adding timer wheel level {}", self.upper_levels.len() + 1);
+                    self.upper_levels.push(vec![Vec::new(); self.no_slots]);
+                    self.counts.push(0);
+                }
+                let slot = match self.span(level) {
+                    Some(span) => ((expiry - 1) / span % n) as usize,
+                    None => 0,
+                };
+                self.upper_levels[level - 1][slot].push((expiry, entry));
+                self.counts[level] += 1;
+                return (
+                    (level * self.no_slots + slot) as u32,
+                    (self.upper_levels[level - 1][slot].len() - 1) as u32,
+                );
+            }
+            level += 1;
+        }
+    }
+
+    /// moves the events of the upper level slots which start at self.current one level down,
+    /// highest level first, so that events can fall through several levels at once
+    fn cascade(&mut self) {
+        for level in (1..self.upper_levels.len() + 1).rev() {
+            let span = match self.span(level) {
+                Some(span) => span,
+                None => continue,
+            };
+            if self.current % span == 0 {
+                let slot = (self.current / span % self.no_slots as u64) as usize;
+                if self.upper_levels[level - 1][slot].len() > 0 {
+                    let events: Vec<(u64, Entry<T>)> = self.upper_levels[level - 1][slot].drain(..).collect();
+                    debug!("cascading {} events from level {}, slot {}", events.len(), level, slot);
+                    self.counts[level] -= events.len();
+                    // cancelled events are dropped here
+                    for (expiry, entry) in events.into_iter().filter(|e| e.1.what.is_some()) {
+                        let id = entry.id;
+                        let location = self.insert(expiry, entry);
+                        self.handles.relocate(id, location);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn tick(&mut self, now: &u64) -> (Option<Expired<T>>, bool) {
+        if self.start != 0 {
+            // only when the wheel has been started
+            let advance = (*now - self.start) / self.resolution_cycles;
+            let n = self.no_slots as u64;
+            while self.current < advance {
+                self.cascade();
+                let slot = (self.current % n) as usize;
+                self.current += 1;
+                if self.slots[slot].len() > 0 {
+                    debug!(
+                        "processing slot {} with {} events, {} slots left to process",
+                        slot,
+                        self.slots[slot].len(),
+                        advance - self.current
+                    );
+                    self.counts[0] -= self.slots[slot].len();
+                    return (
+                        Some(expire(&mut self.handles, &mut self.slots[slot])),
+                        self.current < advance,
+                    );
+                }
+                if self.counts[0] == 0 {
+                    // nothing on level 0, skip ahead to the next cascading point or right to the end
+                    if self.counts.iter().all(|c| *c == 0) {
+                        self.current = advance;
+                    } else {
+                        self.current = min(advance, (self.current + n - 1) / n * n);
+                    }
+                }
+            }
+        }
+        (None, false)
+    }
+
+    /// schedules a new element and returns the handle for cancelling or rescheduling it,
+    /// the handle stays valid when the element cascades to a lower level
+    pub fn schedule(&mut self, after_cycles: &u64, what: T) -> TimerHandle
+    where
+        T: Debug,
+    {
+        let now = self.clock.now();
+        //initialize start time
+        if self.start == 0 {
+            self.start = now - self.resolution_cycles;
+        }
+        // saturates, an event scheduled after u64::MAX cycles never fires
+        let expiry = after_cycles.saturating_add(now - self.start) / self.resolution_cycles;
+        debug!("scheduling {:?} for slot expiry {}", what, expiry);
+        let handle = self.handles.allocate((0, 0));
+        let location = self.insert(
+            expiry,
+            Entry {
+                id: handle.id,
+                what: Some(what),
+            },
+        );
+        self.handles.relocate(handle.id, location);
+        handle
+    }
+
+    /// schedules a new element after a duration, system_data converts it into cycles
+    pub fn schedule_after(&mut self, after: Duration, what: T, system_data: &SystemData) -> TimerHandle
+    where
+        T: Debug,
+    {
+        self.schedule(&system_data.duration_to_cycles(&after), what)
+    }
+
+    #[inline]
+    fn entry_mut(&mut self, location: (u32, u32)) -> &mut Entry<T> {
+        let level = location.0 as usize / self.no_slots;
+        let slot = location.0 as usize % self.no_slots;
+        if level == 0 {
+            &mut self.slots[slot][location.1 as usize]
         } else {
-            None
+            &mut self.upper_levels[level - 1][slot][location.1 as usize].1
         }
     }
+
+    /// removes the event from the wheel, returns None if it has already fired or has been cancelled
+    pub fn cancel(&mut self, handle: &TimerHandle) -> Option<T> {
+        let location = self.handles.location(handle)?;
+        let old = self.entry_mut(location).what.take();
+        self.handles.release(handle.id);
+        old
+    }
+
+    /// cancels the event and schedules it again after_cycles from now,
+    /// returns None and leaves the wheel untouched if the handle is stale
+    pub fn reschedule(&mut self, handle: &TimerHandle, after_cycles: &u64) -> Option<TimerHandle>
+    where
+        T: Debug,
+    {
+        let what = self.cancel(handle)?;
+        Some(self.schedule(after_cycles, what))
+    }
+
+    /// replaces the element of a pending event, returns None if the handle is stale
+    #[inline]
+    pub fn replace(&mut self, handle: &TimerHandle, new_element: T) -> Option<T> {
+        let location = self.handles.location(handle)?;
+        debug!("replace: slot = {}, index = {}", location.0, location.1);
+        self.entry_mut(location).what.replace(new_element)
+    }
 }
 
 #[cfg(test)]
 // run this test with --release flag, it is real-time sensitive
 mod tests {
     use super::*;
+    use clock::ManualClock;
     use std::thread;
     use std::time::Duration;
-    use super::super::system::SystemData;
+    use super::super::system::{CpuTopology, SystemData};
 
     #[test]
     fn event_timing() {
-        let system_data = SystemData::detect();
+        let system_data = SystemData::detect().unwrap();
         let milli_to_cycles: u64 = system_data.cpu_clock / 1000;
 
-        let start = unsafe { _rdtsc() };
+        let start = TscClock.now();
         println!("start = {:?}", start);
 
         let mut wheel: TimerWheel<u16> = TimerWheel::new(128, 16 * milli_to_cycles, 128);
@@ -154,7 +620,7 @@ mod tests {
         for _i in 0..1024 {
             // proceed with roughly 2 ms ticks
             thread::sleep(Duration::from_millis(2));
-            let now = unsafe { _rdtsc() };
+            let now = TscClock.now();
             match wheel.tick(&now) {
                 (Some(mut drain), _more) => {
                     let event = drain.next();
@@ -177,7 +643,7 @@ mod tests {
         for _i in 0..1024 {
             // proceed with roughly 2 ms ticks
             thread::sleep(Duration::from_millis(2));
-            let now = unsafe { _rdtsc() };
+            let now = TscClock.now();
             match wheel.tick(&now) {
                 (Some(mut drain), _more) => {
                     let event = drain.next();
@@ -192,9 +658,32 @@ mod tests {
         assert!(found_it);
     }
 
+    #[test]
+    fn event_timing_with_manual_clock() {
+        let resolution = 16000u64;
+        let clock = ManualClock::new(1000000);
+        let mut wheel = TimerWheel::with_clock(128, resolution, 128, clock.clone());
+        for j in 0..128 {
+            wheel.schedule(&(j * resolution + resolution / 2), j);
+        }
+        let mut n_found = 0;
+        for j in 0..128 {
+            let now = clock.advance(resolution);
+            match wheel.tick(&now) {
+                (Some(mut drain), _more) => {
+                    assert_eq!(drain.next(), Some(j));
+                    assert_eq!(drain.next(), None);
+                    n_found += 1;
+                }
+                (None, _more) => assert!(false), // there must be one event in each slot
+            }
+        }
+        assert_eq!(n_found, 128);
+    }
+
     #[test]
     fn replace_element_in_timer_wheel() {
-        let system_data = SystemData::detect();
+        let system_data = SystemData::detect().unwrap();
         let milli_to_cycles: u64 = system_data.cpu_clock / 1000;
 
         let mut wheel: TimerWheel<u16> = TimerWheel::new(128, 16 * milli_to_cycles, 128);
@@ -206,8 +695,177 @@ mod tests {
         }
         // add the test element
         let n_millis = 100;
-        let slot_and_index = wheel.schedule(&((n_millis as u64) * milli_to_cycles), n_millis);
-        let old = wheel.replace(slot_and_index, 101);
+        let handle = wheel.schedule(&((n_millis as u64) * milli_to_cycles), n_millis);
+        let old = wheel.replace(&handle, 101);
         assert_eq!(old.unwrap(), n_millis);
     }
+
+    #[test]
+    fn hierarchical_long_timeouts() {
+        // level 0 covers only 8 slots
+        let resolution = 1000u64;
+        let clock = ManualClock::new(1000000);
+        let mut wheel = HierarchicalTimerWheel::with_clock(8, resolution, 8, clock.clone());
+        let timeouts: Vec<u64> = vec![3, 7, 8, 9, 17, 63, 64, 65, 100, 511, 512, 600, 5000];
+        for t in &timeouts {
+            wheel.schedule(&(t * resolution), *t);
+        }
+        assert!(wheel.levels() >= 4);
+
+        let mut fired = Vec::new();
+        for j in 1..6000u64 {
+            let now = clock.advance(resolution);
+            loop {
+                match wheel.tick(&now) {
+                    (Some(drain), more) => {
+                        for t in drain {
+                            assert_eq!(t, j, "event {} fired at {}", t, j);
+                            fired.push(t);
+                        }
+                        if !more {
+                            break;
+                        }
+                    }
+                    (None, _more) => break,
+                }
+            }
+        }
+        fired.sort();
+        assert_eq!(fired, timeouts);
+    }
+
+    #[test]
+    fn clamp_long_timeouts() {
+        let resolution = 1000u64;
+        let clock = ManualClock::new(1000000);
+        let mut wheel = TimerWheel::with_clock(8, resolution, 8, clock.clone());
+        assert_eq!(wheel.get_max_timeout_cycles(), 7 * resolution);
+        // without clamping, 10 slots wrap into slot 2
+        wheel.schedule(&(10 * resolution), 10);
+        for j in 1..7 {
+            assert!(wheel.tick(&clock.advance(resolution)).0.is_none(), "fired at {}", j);
+        }
+        assert_eq!(wheel.tick(&clock.advance(resolution)).0.unwrap().next(), Some(10));
+
+        let mut wheel = HierarchicalTimerWheel::with_clock(8, resolution, 8, clock.clone());
+        let handle = wheel.schedule(&u64::max_value(), 1);
+        wheel.schedule(&(10 * resolution), 10);
+        for _ in 1..10 {
+            assert!(wheel.tick(&clock.advance(resolution)).0.is_none());
+        }
+        assert_eq!(wheel.tick(&clock.advance(resolution)).0.unwrap().next(), Some(10));
+        assert_eq!(wheel.cancel(&handle), Some(1));
+    }
+
+    #[test]
+    fn replace_element_in_hierarchical_wheel() {
+        let mut wheel = HierarchicalTimerWheel::with_clock(16, 1000, 16, ManualClock::new(1000000));
+        let handle = wheel.schedule(&5000, 5);
+        assert_eq!(wheel.replace(&handle, 6).unwrap(), 5);
+        let handle = wheel.schedule(&500000, 500);
+        assert!(wheel.levels() > 1);
+        assert_eq!(wheel.replace(&handle, 501).unwrap(), 500);
+    }
+
+    #[test]
+    fn schedule_after_duration() {
+        let system_data = SystemData {
+            cpu_clock: 2_000_000_000,
+            topology: CpuTopology::default(),
+        };
+        let mut wheel: TimerWheel<u16> = TimerWheel::with_resolution(Duration::from_millis(10), 128, &system_data);
+        assert_eq!(wheel.resolution(), 20_000_000);
+        assert_eq!(
+            wheel.get_max_timeout_cycles(),
+            system_data.duration_to_cycles(&Duration::from_millis(1270))
+        );
+        let handle = wheel.schedule_after(Duration::from_millis(200), 200, &system_data);
+        assert_eq!(wheel.cancel(&handle), Some(200));
+
+        let mut wheel: HierarchicalTimerWheel<u16> =
+            HierarchicalTimerWheel::with_resolution(Duration::from_millis(10), 16, &system_data);
+        let handle = wheel.schedule_after(Duration::from_secs(60), 60, &system_data);
+        assert!(wheel.levels() > 2);
+        assert_eq!(wheel.cancel(&handle), Some(60));
+
+        // any wheel works, e.g. one with a manual clock and a resolution below one cycle
+        let clock = ManualClock::new(1000000);
+        let mut wheel = TimerWheel::with_resolution_and_clock(Duration::new(0, 0), 16, &system_data, clock.clone());
+        assert_eq!(wheel.resolution(), 1);
+        let handle = wheel.schedule_after(Duration::from_nanos(4), 4, &system_data);
+        assert_eq!(wheel.cancel(&handle), Some(4));
+        wheel.schedule_after(Duration::from_nanos(4), 4, &system_data);
+        let now = clock.advance(8);
+        assert_eq!(wheel.tick(&now).0.unwrap().next(), Some(4));
+        let mut wheel: HierarchicalTimerWheel<u16, _> = HierarchicalTimerWheel::with_resolution_and_clock(
+            Duration::from_millis(1),
+            8,
+            &system_data,
+            ManualClock::new(1_000_000_000),
+        );
+        assert_eq!(wheel.resolution(), 2_000_000);
+        let handle = wheel.schedule_after(Duration::from_millis(100), 100, &system_data);
+        assert_eq!(wheel.cancel(&handle), Some(100));
+    }
+
+    #[test]
+    fn cancel_with_stale_handles() {
+        let resolution = 1000u64;
+        let clock = ManualClock::new(1000000);
+        let mut wheel = TimerWheel::with_clock(4, resolution, 4, clock.clone());
+        let first = wheel.schedule(&resolution, 1);
+        // drain the slot of the first event
+        let mut fired = Vec::new();
+        for _j in 1..4u64 {
+            if let (Some(drain), _more) = wheel.tick(&clock.advance(resolution)) {
+                fired.extend(drain);
+            }
+        }
+        assert_eq!(fired, vec![1]);
+        // refill the same slot, the stale handle must not hit the new event
+        let second = wheel.schedule(&(4 * resolution), 2);
+        assert_eq!(wheel.cancel(&first), None);
+        assert_eq!(wheel.reschedule(&first, &resolution), None);
+        assert_eq!(wheel.cancel(&second), Some(2));
+        assert_eq!(wheel.cancel(&second), None);
+        let third = wheel.schedule(&(2 * resolution), 3);
+        assert!(wheel.replace(&second, 4).is_none());
+        let third = wheel.reschedule(&third, &(3 * resolution)).unwrap();
+        let mut fired = Vec::new();
+        for _j in 4..12u64 {
+            if let (Some(drain), _more) = wheel.tick(&clock.advance(resolution)) {
+                fired.extend(drain);
+            }
+        }
+        assert_eq!(fired, vec![3]);
+        assert_eq!(wheel.cancel(&third), None);
+    }
+
+    #[test]
+    fn cancel_after_cascading() {
+        let resolution = 1000u64;
+        let clock = ManualClock::new(1000000);
+        let mut wheel = HierarchicalTimerWheel::with_clock(4, resolution, 4, clock.clone());
+        let long = wheel.schedule(&(40 * resolution), 40);
+        let cancelled = wheel.schedule(&(20 * resolution), 20);
+        let mut fired = Vec::new();
+        for j in 1..30u64 {
+            if let (Some(drain), _more) = wheel.tick(&clock.advance(resolution)) {
+                fired.extend(drain);
+            }
+            if j == 17 {
+                // both events have cascaded by now
+                assert_eq!(wheel.cancel(&cancelled), Some(20));
+            }
+        }
+        assert!(fired.is_empty());
+        assert_eq!(wheel.replace(&long, 41), Some(40));
+        for _j in 30..50u64 {
+            if let (Some(drain), _more) = wheel.tick(&clock.advance(resolution)) {
+                fired.extend(drain);
+            }
+        }
+        assert_eq!(fired, vec![41]);
+        assert_eq!(wheel.cancel(&long), None);
+    }
 }
@@ -1,14 +1,123 @@
-use rand::thread_rng;
+use rand::{thread_rng, Rng, SeedableRng};
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
+use std::time::Duration;
 
-#[derive(Deserialize, Clone)]
+use serde::de::{self, Deserialize, Deserializer};
+
+use system::SystemData;
+use tcp_common::TcpState;
+
+// in millis
+const DEFAULT_SYN_TIMEOUT: u64 = 1000;
+const DEFAULT_ESTABLISHED_TIMEOUT: u64 = 200;
+const DEFAULT_FIN_WAIT_TIMEOUT: u64 = 1000;
+const DEFAULT_TIME_WAIT_TIMEOUT: u64 = 2000;
+
+/// parses durations like "200ms", "5s" or "1.5m", units are ns, us, ms, s, m(in) and h
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+    let (number, unit) = (&s[..split], s[split..].trim());
+    let nanos_per_unit: u64 = match unit {
+        "ns" => 1,
+        "us" | "µs" => 1_000,
+        "ms" => 1_000_000,
+        "s" => 1_000_000_000,
+        "m" | "min" => 60_000_000_000,
+        "h" => 3_600_000_000_000,
+        _ => return Err(format!("invalid unit in duration '{}'", s)),
+    };
+    let value: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid number in duration '{}'", s))?;
+    let nanos = value * nanos_per_unit as f64;
+    if !nanos.is_finite() || nanos > u64::MAX as f64 {
+        return Err(format!("duration '{}' too large", s));
+    }
+    Ok(Duration::from_nanos(nanos as u64))
+}
+
+fn duration_to_millis(duration: Duration) -> u64 {
+    let millis = duration
+        .as_secs()
+        .saturating_mul(1000)
+        .saturating_add(duration.subsec_nanos() as u64 / 1_000_000);
+    if duration.subsec_nanos() % 1_000_000 > 0 {
+        millis.saturating_add(1)
+    } else {
+        millis
+    }
+}
+
+/// accepts integers as milliseconds, as in former configurations, and strings like "200ms" or "5s",
+/// the latter are rounded up to whole milliseconds
+fn deserialize_millis<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Millis(u64),
+        Text(String),
+    }
+    match Option::<Raw>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Raw::Millis(millis)) => Ok(Some(millis)),
+        Some(Raw::Text(text)) => parse_duration(&text)
+            .map(|duration| Some(duration_to_millis(duration)))
+            .map_err(de::Error::custom),
+    }
+}
+
+/// a timeout exceeds the maximum supported by the timer wheel
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeoutTooLarge {
+    pub name: &'static str,
+    pub timeout: Duration,
+    pub max: Duration,
+}
+
+impl fmt::Display for TimeoutTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "timeout {} = {:?} exceeds the maximum timeout {:?}",
+            self.name, self.timeout, self.max
+        )
+    }
+}
+
+/// timeouts of connections per TcpState in millis, in the toml configuration either millis or strings like "5s"
+#[derive(Deserialize, Clone, Debug, PartialEq)]
 pub struct Timeouts {
+    /// waiting for the SYN-ACK
+    #[serde(default, deserialize_with = "deserialize_millis")]
+    pub syn_sent: Option<u64>, // in millis
+    /// waiting for the SYN or the ACK of the handshake
+    #[serde(default, deserialize_with = "deserialize_millis")]
+    pub syn_received: Option<u64>, // in millis
+    /// idle established connections
+    #[serde(default, deserialize_with = "deserialize_millis")]
     pub established: Option<u64>, // in millis
+    /// connections in one of the closing states
+    #[serde(default, deserialize_with = "deserialize_millis")]
+    pub fin_wait: Option<u64>, // in millis
+    /// lingering of closed connections, like TIME_WAIT
+    #[serde(default, deserialize_with = "deserialize_millis")]
+    pub time_wait: Option<u64>, // in millis
 }
 
 impl Default for Timeouts {
     fn default() -> Timeouts {
-        Timeouts { established: Some(200) }
+        Timeouts {
+            syn_sent: Some(DEFAULT_SYN_TIMEOUT),
+            syn_received: Some(DEFAULT_SYN_TIMEOUT),
+            established: Some(DEFAULT_ESTABLISHED_TIMEOUT),
+            fin_wait: Some(DEFAULT_FIN_WAIT_TIMEOUT),
+            time_wait: Some(DEFAULT_TIME_WAIT_TIMEOUT),
+        }
     }
 }
 
@@ -17,12 +126,71 @@ impl Timeouts {
         let mut t = Timeouts::default();
         if timeouts.is_some() {
             let timeouts = timeouts.clone().unwrap();
-            if timeouts.established.is_some() {
-                t.established = timeouts.established;
-            }
+            t.syn_sent = timeouts.syn_sent.or(t.syn_sent);
+            t.syn_received = timeouts.syn_received.or(t.syn_received);
+            t.established = timeouts.established.or(t.established);
+            t.fin_wait = timeouts.fin_wait.or(t.fin_wait);
+            t.time_wait = timeouts.time_wait.or(t.time_wait);
         }
         t
     }
+
+    pub fn syn_sent_duration(&self) -> Option<Duration> {
+        self.syn_sent.map(Duration::from_millis)
+    }
+
+    pub fn syn_received_duration(&self) -> Option<Duration> {
+        self.syn_received.map(Duration::from_millis)
+    }
+
+    pub fn established_duration(&self) -> Option<Duration> {
+        self.established.map(Duration::from_millis)
+    }
+
+    pub fn fin_wait_duration(&self) -> Option<Duration> {
+        self.fin_wait.map(Duration::from_millis)
+    }
+
+    pub fn time_wait_duration(&self) -> Option<Duration> {
+        self.time_wait.map(Duration::from_millis)
+    }
+
+    /// the timeout of connections in state, missing values are replaced by the defaults
+    pub fn for_state(&self, state: TcpState) -> Duration {
+        let millis = match state {
+            TcpState::Listen | TcpState::SynReceived => self.syn_received.unwrap_or(DEFAULT_SYN_TIMEOUT),
+            TcpState::SynSent => self.syn_sent.unwrap_or(DEFAULT_SYN_TIMEOUT),
+            TcpState::Established => self.established.unwrap_or(DEFAULT_ESTABLISHED_TIMEOUT),
+            TcpState::CloseWait | TcpState::LastAck | TcpState::FinWait1 | TcpState::Closing | TcpState::FinWait2 => {
+                self.fin_wait.unwrap_or(DEFAULT_FIN_WAIT_TIMEOUT)
+            }
+            TcpState::Closed => self.time_wait.unwrap_or(DEFAULT_TIME_WAIT_TIMEOUT),
+        };
+        Duration::from_millis(millis)
+    }
+
+    /// checks that no timeout exceeds max
+    pub fn validate(&self, max: Duration) -> Result<(), TimeoutTooLarge> {
+        for &(name, timeout) in &[
+            ("syn_sent", self.syn_sent_duration()),
+            ("syn_received", self.syn_received_duration()),
+            ("established", self.established_duration()),
+            ("fin_wait", self.fin_wait_duration()),
+            ("time_wait", self.time_wait_duration()),
+        ] {
+            match timeout {
+                Some(timeout) if timeout > max => return Err(TimeoutTooLarge { name, timeout, max }),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// checks that all timeouts can be scheduled in a timer wheel, max_timeout_cycles is the result of its
+    /// get_max_timeout_cycles
+    pub fn validate_for_wheel(&self, max_timeout_cycles: u64, system_data: &SystemData) -> Result<(), TimeoutTooLarge> {
+        self.validate(system_data.cycles_to_duration(max_timeout_cycles))
+    }
 }
 
 pub struct TimeAdder {
@@ -99,23 +267,349 @@ impl TimeAdder {
     }
 }
 
+/// percentiles of recorded values, in cycles or as Duration
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Percentiles<T> {
+    pub count: u64,
+    pub min: T,
+    pub mean: T,
+    pub p50: T,
+    pub p90: T,
+    pub p99: T,
+    pub p999: T,
+    pub max: T,
+}
+
+impl Percentiles<u64> {
+    pub fn to_durations(&self, system_data: &SystemData) -> Percentiles<Duration> {
+        Percentiles {
+            count: self.count,
+            min: system_data.cycles_to_duration(self.min),
+            mean: system_data.cycles_to_duration(self.mean),
+            p50: system_data.cycles_to_duration(self.p50),
+            p90: system_data.cycles_to_duration(self.p90),
+            p99: system_data.cycles_to_duration(self.p99),
+            p999: system_data.cycles_to_duration(self.p999),
+            max: system_data.cycles_to_duration(self.max),
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Display for Percentiles<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "count= {}, min= {:?}, mean= {:?}, p50= {:?}, p90= {:?}, p99= {:?}, p99.9= {:?}, max= {:?}",
+            self.count, self.min, self.mean, self.p50, self.p90, self.p99, self.p999, self.max
+        )
+    }
+}
+
+/// histogram with log-linear buckets, like a HDR histogram: values below 2^precision_bits are recorded
+/// exactly, larger values with a relative error below 2^(1-precision_bits). Histograms of the same precision,
+/// e.g. from different cores, can be merged.
+#[derive(Clone)]
+pub struct Histogram {
+    precision_bits: u32,
+    counts: Vec<u64>,
+    count: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    /// relative error below 1.6%, takes about 30 kB
+    fn default() -> Histogram {
+        Histogram::new(7)
+    }
+}
+
+impl Histogram {
+    pub fn new(precision_bits: u32) -> Histogram {
+        assert!((1..=16).contains(&precision_bits));
+        let half = 1usize << (precision_bits - 1);
+        Histogram {
+            precision_bits,
+            counts: vec![0; (66 - precision_bits as usize) * half],
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    #[inline]
+    fn index(&self, value: u64) -> usize {
+        if value < 1u64 << self.precision_bits {
+            value as usize
+        } else {
+            // the number of low bits which are dropped
+            let magnitude = 64 - value.leading_zeros() - self.precision_bits;
+            ((magnitude as usize) << (self.precision_bits - 1)) + (value >> magnitude) as usize
+        }
+    }
+
+    /// the largest value which is recorded in the bucket
+    #[inline]
+    fn highest_equivalent(&self, index: usize) -> u64 {
+        if index < 1usize << self.precision_bits {
+            index as u64
+        } else {
+            let magnitude = (index >> (self.precision_bits - 1)) - 1;
+            let sub_bucket = (index - (magnitude << (self.precision_bits - 1))) as u128;
+            // may exceed u64 for the top most bucket
+            (((sub_bucket + 1) << magnitude) - 1).min(u64::MAX as u128) as u64
+        }
+    }
+
+    #[inline]
+    pub fn record(&mut self, value: u64) {
+        self.record_n(value, 1);
+    }
+
+    #[inline]
+    pub fn record_n(&mut self, value: u64, n: u64) {
+        let index = self.index(value);
+        self.counts[index] += n;
+        self.count += n;
+        self.sum += value as u128 * n as u128;
+        self.min = cmp::min(self.min, value);
+        self.max = cmp::max(self.max, value);
+    }
+
+    /// adds the values recorded by other, both must have the same precision
+    pub fn merge(&mut self, other: &Histogram) {
+        assert_eq!(self.precision_bits, other.precision_bits);
+        for (c, o) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *c += *o;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = cmp::min(self.min, other.min);
+        self.max = cmp::max(self.max, other.max);
+    }
+
+    pub fn reset(&mut self) {
+        for c in self.counts.iter_mut() {
+            *c = 0;
+        }
+        self.count = 0;
+        self.sum = 0;
+        self.min = u64::MAX;
+        self.max = 0;
+    }
+
+    #[inline]
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn min(&self) -> u64 {
+        if self.count == 0 {
+            0
+        } else {
+            self.min
+        }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn mean(&self) -> u64 {
+        if self.count == 0 {
+            0
+        } else {
+            (self.sum / self.count as u128) as u64
+        }
+    }
+
+    /// smallest value which is larger or equal than percentile % of the recorded values, within the precision
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = cmp::max(1, (percentile / 100.0 * self.count as f64).ceil() as u64);
+        let mut seen = 0;
+        for (index, c) in self.counts.iter().enumerate() {
+            seen += *c;
+            if seen >= rank {
+                return cmp::min(self.highest_equivalent(index), self.max);
+            }
+        }
+        self.max
+    }
+
+    pub fn percentiles(&self) -> Percentiles<u64> {
+        Percentiles {
+            count: self.count,
+            min: self.min(),
+            mean: self.mean(),
+            p50: self.percentile(50.0),
+            p90: self.percentile(90.0),
+            p99: self.percentile(99.0),
+            p999: self.percentile(99.9),
+            max: self.max(),
+        }
+    }
+
+    /// logs the percentiles at info level, converted to Duration if system_data is given
+    pub fn log(&self, name: &str, system_data: Option<&SystemData>) {
+        match system_data {
+            Some(system_data) => info!("{}: {}", name, self.percentiles().to_durations(system_data)),
+            None => info!("{}: {} (cycles)", name, self.percentiles()),
+        }
+    }
+}
+
 pub fn shuffle_ports(first_port: u16, last_port: u16) -> Vec<u16> {
-    let mut vec: Vec<u16> = (first_port..last_port + 1).collect();
+    shuffle_ports_with_rng(first_port, last_port, &mut thread_rng())
+}
+
+pub fn shuffle_ports_with_rng<R: Rng>(first_port: u16, last_port: u16, rng: &mut R) -> Vec<u16> {
+    let mut vec: Vec<u16> = (first_port..=last_port).collect();
     {
         let slice: &mut [u16] = &mut vec;
-        slice.shuffle(&mut thread_rng());
+        slice.shuffle(rng);
     }
     vec
 }
 
-use std::collections::{VecDeque, BTreeMap};
+/// first TCP port of a pipeline with FlowSteeringMode::Port, the pipeline owns all ports p with
+/// p & port_mask == tcp_port_base(port_mask, pipeline)
+#[inline]
+pub fn tcp_port_base(port_mask: u16, pipeline: u16) -> u16 {
+    port_mask - pipeline * (!port_mask + 1)
+}
+
+/// hands out ports from a shuffled pool, released ports are quarantined for some time (like TIME_WAIT)
+/// before they are handed out again. Time stamps are in cycles.
+pub struct PortAllocator {
+    free: VecDeque<u16>,
+    /// released ports with their release time, oldest first
+    quarantine: VecDeque<(u64, u16)>,
+    quarantine_cycles: u64,
+    /// bitmap of ports which are handed out
+    in_use: Vec<u64>,
+}
+
+impl PortAllocator {
+    pub fn new(first_port: u16, last_port: u16, quarantine_cycles: u64) -> PortAllocator {
+        PortAllocator::from_pool(shuffle_ports(first_port, last_port), quarantine_cycles)
+    }
+
+    /// the same seed results in the same sequence of ports, e.g. for reproducible test runs
+    pub fn with_seed(first_port: u16, last_port: u16, quarantine_cycles: u64, seed: u64) -> PortAllocator {
+        let mut rng = StdRng::seed_from_u64(seed);
+        PortAllocator::from_pool(
+            shuffle_ports_with_rng(first_port, last_port, &mut rng),
+            quarantine_cycles,
+        )
+    }
+
+    /// allocator for the ports which are steered to the queue of the pipeline, see tcp_port_base
+    pub fn for_pipeline(port_mask: u16, pipeline: u16, quarantine_cycles: u64, seed: Option<u64>) -> PortAllocator {
+        let first_port = tcp_port_base(port_mask, pipeline);
+        let last_port = first_port | !port_mask;
+        // port 0 is not usable
+        let first_port = cmp::max(first_port, 1);
+        match seed {
+            Some(seed) => PortAllocator::with_seed(first_port, last_port, quarantine_cycles, seed),
+            None => PortAllocator::new(first_port, last_port, quarantine_cycles),
+        }
+    }
+
+    fn from_pool(pool: Vec<u16>, quarantine_cycles: u64) -> PortAllocator {
+        PortAllocator {
+            free: pool.into_iter().collect(),
+            quarantine: VecDeque::new(),
+            quarantine_cycles,
+            in_use: vec![0; 0x10000 / 64],
+        }
+    }
+
+    #[inline]
+    fn is_in_use(&self, port: u16) -> bool {
+        self.in_use[port as usize / 64] & (1u64 << (port % 64)) != 0
+    }
+
+    #[inline]
+    fn set_in_use(&mut self, port: u16, in_use: bool) {
+        if in_use {
+            self.in_use[port as usize / 64] |= 1u64 << (port % 64);
+        } else {
+            self.in_use[port as usize / 64] &= !(1u64 << (port % 64));
+        }
+    }
+
+    /// moves ports whose quarantine is over back to the free ports
+    fn release_expired(&mut self, now: u64) {
+        while let Some(&(released, port)) = self.quarantine.front() {
+            if now.wrapping_sub(released) < self.quarantine_cycles {
+                break;
+            }
+            self.quarantine.pop_front();
+            self.free.push_back(port);
+        }
+    }
+
+    /// returns None, if all ports are in use or quarantined
+    #[inline]
+    pub fn allocate(&mut self, now: u64) -> Option<u16> {
+        self.release_expired(now);
+        let port = self.free.pop_front()?;
+        self.set_in_use(port, true);
+        Some(port)
+    }
+
+    /// returns false, if the port was not handed out by this allocator
+    #[inline]
+    pub fn release(&mut self, port: u16, now: u64) -> bool {
+        if !self.is_in_use(port) {
+            return false;
+        }
+        self.set_in_use(port, false);
+        if self.quarantine_cycles == 0 {
+            self.free.push_back(port);
+        } else {
+            self.quarantine.push_back((now, port));
+        }
+        true
+    }
+
+    pub fn free_ports(&self) -> usize {
+        self.free.len()
+    }
+
+    pub fn quarantined_ports(&self) -> usize {
+        self.quarantine.len()
+    }
+}
+
+use std::cmp;
+use std::collections::{btree_map, VecDeque, BTreeMap};
+use std::fmt;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::ops::Shr;
 use std::ops::BitAnd;
 
 const CHUNK_BITS: usize = 8;
 const CHUNK_SIZE: usize = 1usize << CHUNK_BITS;
 const ROOT_SIZE: usize = 1usize << (16 - CHUNK_BITS);
-const HEAP_SIZE: usize = 0x1000;
+/// chunks are addressed by u32 in the PortMap
+const MAX_CHUNKS: usize = u32::MAX as usize;
+
+/// the limit of chunks given to Sock2Index::with_max_chunks is reached
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutOfChunks;
+
+impl fmt::Display for OutOfChunks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "out of chunks")
+    }
+}
 
 #[derive(Clone, Copy)]
 struct Chunk<T>
@@ -162,19 +656,49 @@ impl<T: Copy + PartialEq> Chunk<T> {
     }
 }
 
+/// memory usage of a Sock2Index
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Sock2IndexStats {
+    pub ips: usize,
+    /// chunks in the heap, including the free ones
+    pub chunks: usize,
+    pub used_chunks: usize,
+    pub used_slots: usize,
+    /// approximate, without the overhead of the BTreeMap
+    pub bytes: usize,
+}
+
+impl fmt::Display for Sock2IndexStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ips= {}, chunks= {} ({} used), used slots= {}, bytes= {}",
+            self.ips, self.chunks, self.used_chunks, self.used_slots, self.bytes
+        )
+    }
+}
+
+/// chunks are allocated on demand up to max_chunks and are reused after being released
 struct ChunkHeap<T>
 where
     T: Copy + PartialEq,
 {
     heap: Vec<Chunk<T>>,
     free_chunks: VecDeque<usize>,
+    max_chunks: usize,
+    empty: T,
 }
 
 impl<T: Copy + PartialEq> ChunkHeap<T> {
     fn new(init: T) -> ChunkHeap<T> {
+        ChunkHeap::with_max_chunks(init, MAX_CHUNKS)
+    }
+    fn with_max_chunks(init: T, max_chunks: usize) -> ChunkHeap<T> {
         ChunkHeap {
-            heap: vec![Chunk::new(init); HEAP_SIZE],
-            free_chunks: (1..HEAP_SIZE + 1).collect(),
+            heap: Vec::new(),
+            free_chunks: VecDeque::new(),
+            max_chunks: cmp::min(max_chunks, MAX_CHUNKS),
+            empty: init,
         }
     }
     // index i must be > 0, as we use 0 for indicating unused slots
@@ -188,11 +712,32 @@ impl<T: Copy + PartialEq> ChunkHeap<T> {
     }
     #[inline]
     fn allocate(&mut self) -> Option<usize> {
-        let ix = self.free_chunks.pop_front();
-        if ix.is_some() {
-            self.get_mut(ix.unwrap()).allocated = true;
+        let ix = match self.free_chunks.pop_front() {
+            Some(ix) => ix,
+            None if self.heap.len() < self.max_chunks => {
+                self.heap.push(Chunk::new(self.empty));
+                self.heap.len()
+            }
+            None => return None,
+        };
+        self.get_mut(ix).allocated = true;
+        Some(ix)
+    }
+    /// returns an empty chunk to the free chunks
+    #[inline]
+    fn release(&mut self, i: usize) {
+        let chunk = self.get_mut(i);
+        debug_assert!(chunk.used_slots == 0);
+        chunk.allocated = false;
+        self.free_chunks.push_back(i);
+    }
+    /// empties and releases all chunks
+    fn clear(&mut self) {
+        let empty = self.empty;
+        for chunk in self.heap.iter_mut().filter(|chunk| chunk.allocated) {
+            *chunk = Chunk::new(empty);
         }
-        ix
+        self.free_chunks = (1..self.heap.len() + 1).collect();
     }
     fn values(&self, filter: fn(&&T) -> bool) -> Vec<T> {
         self.heap
@@ -201,25 +746,49 @@ impl<T: Copy + PartialEq> ChunkHeap<T> {
             .flat_map(|chunk| chunk.chunk.iter().filter(filter).map(|item| *item))
             .collect()
     }
+    fn stats(&self) -> Sock2IndexStats {
+        Sock2IndexStats {
+            ips: 0,
+            chunks: self.heap.len(),
+            used_chunks: self.heap.len() - self.free_chunks.len(),
+            used_slots: self.heap.iter().map(|chunk| chunk.used_slots).sum(),
+            bytes: self.heap.capacity() * mem::size_of::<Chunk<T>>()
+                + self.free_chunks.capacity() * mem::size_of::<usize>(),
+        }
+    }
 }
 
 struct PortMap {
-    root: [u16; ROOT_SIZE],
+    root: [u32; ROOT_SIZE],
+    used_chunks: usize,
 }
 
 impl PortMap {
     fn new() -> PortMap {
-        PortMap { root: [0; ROOT_SIZE] }
+        PortMap {
+            root: [0; ROOT_SIZE],
+            used_chunks: 0,
+        }
+    }
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.used_chunks == 0
     }
     #[inline]
-    fn insert<T: Copy + PartialEq>(&mut self, chunk_heap: &mut ChunkHeap<T>, port: u16, item: T) {
+    fn insert<T: Copy + PartialEq>(
+        &mut self,
+        chunk_heap: &mut ChunkHeap<T>,
+        port: u16,
+        item: T,
+    ) -> Result<Option<T>, OutOfChunks> {
         let high_p = port.shr(CHUNK_BITS as u16) as usize;
         let low_p = port.bitand(CHUNK_SIZE as u16 - 1) as usize;
         if self.root[high_p] == 0 {
             // allocate a chunk
-            self.root[high_p] = chunk_heap.allocate().expect("out of chunks") as u16;
+            self.root[high_p] = chunk_heap.allocate().ok_or(OutOfChunks)? as u32;
+            self.used_chunks += 1;
         }
-        chunk_heap.get_mut(self.root[high_p] as usize).insert(low_p, item);
+        Ok(chunk_heap.get_mut(self.root[high_p] as usize).insert(low_p, item))
     }
     #[inline]
     fn get<'a, T: Copy + PartialEq>(&self, chunk_heap: &'a ChunkHeap<T>, port: u16) -> Option<&'a T> {
@@ -231,6 +800,36 @@ impl PortMap {
             Some(&chunk_heap.get(self.root[high_p] as usize).chunk[low_p])
         }
     }
+    /// removes the items for which f returns false, releases empty chunks and returns the number of removed items
+    fn retain<T: Copy + PartialEq, F>(&mut self, chunk_heap: &mut ChunkHeap<T>, mut f: F) -> usize
+    where
+        F: FnMut(u16, &T) -> bool,
+    {
+        let mut removed = 0;
+        for high_p in 0..ROOT_SIZE {
+            let chunk_ix = self.root[high_p] as usize;
+            if chunk_ix == 0 {
+                continue;
+            }
+            {
+                let chunk = chunk_heap.get_mut(chunk_ix);
+                for low_p in 0..CHUNK_SIZE {
+                    let item = chunk.chunk[low_p];
+                    if item != chunk.empty && !f(((high_p << CHUNK_BITS) + low_p) as u16, &item) {
+                        chunk.remove(low_p);
+                        removed += 1;
+                    }
+                }
+            }
+            if chunk_heap.get(chunk_ix).used_slots == 0 {
+                chunk_heap.release(chunk_ix);
+                self.root[high_p] = 0;
+                self.used_chunks -= 1;
+            }
+        }
+        removed
+    }
+    /// empty chunks are released to the chunk_heap
     #[inline]
     fn remove<T: Copy + PartialEq>(&mut self, chunk_heap: &mut ChunkHeap<T>, port: u16) -> Option<T> {
         let high_p = port.shr(CHUNK_BITS as u16) as usize;
@@ -238,69 +837,319 @@ impl PortMap {
             return None;
         } else {
             let low_p = port.bitand(CHUNK_SIZE as u16 - 1) as usize;
-            let chunk = chunk_heap.get_mut(self.root[high_p] as usize);
-            chunk.remove(low_p)
+            let chunk_ix = self.root[high_p] as usize;
+            let old = chunk_heap.get_mut(chunk_ix).remove(low_p);
+            if chunk_heap.get(chunk_ix).used_slots == 0 {
+                chunk_heap.release(chunk_ix);
+                self.root[high_p] = 0;
+                self.used_chunks -= 1;
+            }
+            old
         }
     }
 }
 
-pub struct Sock2Index {
-    chunk_heap: ChunkHeap<u16>,
-    sock_tree: BTreeMap<u32, PortMap>,
+/// a socket address used as key of Sock2Index: an IP address and a port
+pub trait SockKey: Copy {
+    /// the IP address in a representation which is cheap to compare
+    type Ip: Copy + Ord;
+    fn ip(&self) -> Self::Ip;
+    fn port(&self) -> u16;
+    fn from_parts(ip: Self::Ip, port: u16) -> Self;
+}
+
+impl SockKey for (u32, u16) {
+    type Ip = u32;
+    #[inline]
+    fn ip(&self) -> u32 {
+        self.0
+    }
+    #[inline]
+    fn port(&self) -> u16 {
+        self.1
+    }
+    #[inline]
+    fn from_parts(ip: u32, port: u16) -> (u32, u16) {
+        (ip, port)
+    }
+}
+
+impl SockKey for SocketAddrV4 {
+    type Ip = u32;
+    #[inline]
+    fn ip(&self) -> u32 {
+        u32::from(*SocketAddrV4::ip(self))
+    }
+    #[inline]
+    fn port(&self) -> u16 {
+        SocketAddrV4::port(self)
+    }
+    #[inline]
+    fn from_parts(ip: u32, port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::from(ip), port)
+    }
+}
+
+/// flow info and scope id are not part of the key
+impl SockKey for SocketAddrV6 {
+    type Ip = u128;
+    #[inline]
+    fn ip(&self) -> u128 {
+        u128::from(*SocketAddrV6::ip(self))
+    }
+    #[inline]
+    fn port(&self) -> u16 {
+        SocketAddrV6::port(self)
+    }
+    #[inline]
+    fn from_parts(ip: u128, port: u16) -> SocketAddrV6 {
+        SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0)
+    }
+}
+
+/// for dual stack populations
+impl SockKey for SocketAddr {
+    type Ip = IpAddr;
+    #[inline]
+    fn ip(&self) -> IpAddr {
+        SocketAddr::ip(self)
+    }
+    #[inline]
+    fn port(&self) -> u16 {
+        SocketAddr::port(self)
+    }
+    #[inline]
+    fn from_parts(ip: IpAddr, port: u16) -> SocketAddr {
+        SocketAddr::new(ip, port)
+    }
+}
+
+/// a value stored in Sock2Index, EMPTY marks unused slots and cannot be stored
+pub trait SockValue: Copy + PartialEq {
+    const EMPTY: Self;
+}
+
+impl SockValue for u16 {
+    const EMPTY: u16 = 0;
+}
+
+// the wider types are used for indices into a RecordStore, which start at 0
+
+impl SockValue for u32 {
+    const EMPTY: u32 = u32::MAX;
+}
+
+impl SockValue for u64 {
+    const EMPTY: u64 = u64::MAX;
 }
 
-impl<'a> Sock2Index {
+impl SockValue for usize {
+    const EMPTY: usize = usize::MAX;
+}
+
+pub struct Sock2Index<K: SockKey = (u32, u16), V: SockValue = u16> {
+    chunk_heap: ChunkHeap<V>,
+    sock_tree: BTreeMap<K::Ip, PortMap>,
+    len: usize,
+}
+
+impl Sock2Index {
+    /// index of IPv4 sockets with u16 values, other keys and values are created with default()
     pub fn new() -> Sock2Index {
+        Sock2Index::default()
+    }
+}
+
+impl<K: SockKey, V: SockValue> Default for Sock2Index<K, V> {
+    fn default() -> Sock2Index<K, V> {
+        Sock2Index {
+            chunk_heap: ChunkHeap::new(V::EMPTY),
+            sock_tree: BTreeMap::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<K: SockKey, V: SockValue> Sock2Index<K, V> {
+    /// limits the memory, each chunk holds the values of 256 ports of one IP address
+    pub fn with_max_chunks(max_chunks: usize) -> Sock2Index<K, V> {
         Sock2Index {
-            chunk_heap: ChunkHeap::new(0),
+            chunk_heap: ChunkHeap::with_max_chunks(V::EMPTY, max_chunks),
             sock_tree: BTreeMap::new(),
+            len: 0,
         }
     }
 
     #[inline]
-    pub fn get(&self, sock: &(u32, u16)) -> Option<&u16> {
-        let ip = sock.0;
-        let port = sock.1;
+    pub fn get(&self, sock: &K) -> Option<&V> {
+        let port = sock.port();
         assert!(port > 0);
-        let port_map = self.sock_tree.get(&ip);
+        let port_map = self.sock_tree.get(&sock.ip());
         match port_map {
             None => None,
             Some(port_map) => match port_map.get(&self.chunk_heap, port) {
-                None => None,
-                Some(0) => None,
+                Some(v) if *v == V::EMPTY => None,
                 r => r,
             },
         }
     }
 
+    /// panics, if the limit given to with_max_chunks is reached or index is V::EMPTY
     #[inline]
-    pub fn insert(&mut self, sock: (u32, u16), index: u16) {
-        let ip = sock.0;
-        {
+    pub fn insert(&mut self, sock: K, index: V) {
+        self.try_insert(sock, index).expect("out of chunks");
+    }
+
+    /// panics, if index is V::EMPTY
+    #[inline]
+    pub fn try_insert(&mut self, sock: K, index: V) -> Result<(), OutOfChunks> {
+        assert!(index != V::EMPTY, "the EMPTY value cannot be stored in Sock2Index");
+        let ip = sock.ip();
+        let old = {
             let port_map = self.sock_tree.get_mut(&ip);
             if port_map.is_some() {
-                port_map.unwrap().insert(&mut self.chunk_heap, sock.1, index);
-                return;
+                port_map.unwrap().insert(&mut self.chunk_heap, sock.port(), index)?
+            } else {
+                let mut port_map = PortMap::new();
+                let old = port_map.insert(&mut self.chunk_heap, sock.port(), index)?;
+                self.sock_tree.insert(ip, port_map);
+                old
             }
+        };
+        if old.is_none() {
+            self.len += 1;
         }
-        self.sock_tree.insert(ip, PortMap::new());
-        let port_map = self.sock_tree.get_mut(&ip);
-        port_map.unwrap().insert(&mut self.chunk_heap, sock.1, index);
+        Ok(())
     }
 
     #[inline]
-    pub fn remove(&mut self, sock: &(u32, u16)) -> Option<u16> {
-        let port_map = self.sock_tree.get_mut(&sock.0);
-        if port_map.is_none() {
-            return None;
-        } else {
-            port_map.unwrap().remove(&mut self.chunk_heap, sock.1)
+    pub fn remove(&mut self, sock: &K) -> Option<V> {
+        let ip = sock.ip();
+        let (old, is_empty) = match self.sock_tree.get_mut(&ip) {
+            None => return None,
+            Some(port_map) => (port_map.remove(&mut self.chunk_heap, sock.port()), port_map.is_empty()),
+        };
+        if is_empty {
+            self.sock_tree.remove(&ip);
+        }
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+
+    /// number of sockets in the index
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// iterates over sockets and values, ordered by IP address and port
+    pub fn iter(&self) -> Sock2IndexIter<K, V> {
+        Sock2IndexIter {
+            chunk_heap: &self.chunk_heap,
+            ips: self.sock_tree.iter(),
+            current: None,
+            port: 0,
+        }
+    }
+
+    /// keeps only the sockets for which f returns true
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let chunk_heap = &mut self.chunk_heap;
+        let mut removed = 0;
+        self.sock_tree.retain(|ip, port_map| {
+            removed += port_map.retain(chunk_heap, |port, v| f(&K::from_parts(*ip, port), v));
+            !port_map.is_empty()
+        });
+        self.len -= removed;
+    }
+
+    /// removes all sockets of one IP address and returns them
+    pub fn drain_ip(&mut self, ip: K::Ip) -> Vec<(K, V)> {
+        let mut drained = Vec::new();
+        if let Some(mut port_map) = self.sock_tree.remove(&ip) {
+            port_map.retain(&mut self.chunk_heap, |port, v| {
+                drained.push((K::from_parts(ip, port), *v));
+                false
+            });
         }
+        self.len -= drained.len();
+        drained
+    }
+
+    /// removes all sockets, the chunks are kept for reuse
+    pub fn clear(&mut self) {
+        self.chunk_heap.clear();
+        self.sock_tree.clear();
+        self.len = 0;
+    }
+
+    pub fn memory_stats(&self) -> Sock2IndexStats {
+        let mut stats = self.chunk_heap.stats();
+        stats.ips = self.sock_tree.len();
+        stats.bytes += self.sock_tree.len() * (mem::size_of::<K::Ip>() + mem::size_of::<PortMap>());
+        stats
     }
 
     #[inline]
-    pub fn values(&self) -> Vec<u16> {
-        self.chunk_heap.values(|ix| **ix != 0)
+    pub fn values(&self) -> Vec<V> {
+        self.chunk_heap.values(|ix| **ix != V::EMPTY)
+    }
+}
+
+impl<'a, K: SockKey, V: SockValue> IntoIterator for &'a Sock2Index<K, V> {
+    type Item = (K, V);
+    type IntoIter = Sock2IndexIter<'a, K, V>;
+
+    fn into_iter(self) -> Sock2IndexIter<'a, K, V> {
+        self.iter()
+    }
+}
+
+pub struct Sock2IndexIter<'a, K: SockKey + 'a, V: SockValue + 'a> {
+    chunk_heap: &'a ChunkHeap<V>,
+    ips: btree_map::Iter<'a, K::Ip, PortMap>,
+    current: Option<(&'a K::Ip, &'a PortMap)>,
+    /// next port to look at, u32 to step beyond the last port
+    port: u32,
+}
+
+impl<'a, K: SockKey, V: SockValue> Iterator for Sock2IndexIter<'a, K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        loop {
+            if self.current.is_none() {
+                self.current = Some(self.ips.next()?);
+                self.port = 0;
+            }
+            let (ip, port_map) = self.current.unwrap();
+            while self.port <= u16::MAX as u32 {
+                let high_p = (self.port >> CHUNK_BITS) as usize;
+                let chunk_ix = port_map.root[high_p] as usize;
+                if chunk_ix == 0 {
+                    // skip the ports of the missing chunk
+                    self.port = ((high_p + 1) << CHUNK_BITS) as u32;
+                    continue;
+                }
+                let port = self.port as u16;
+                self.port += 1;
+                let v = self.chunk_heap.get(chunk_ix).chunk[port as usize & (CHUNK_SIZE - 1)];
+                if v != V::EMPTY {
+                    return Some((K::from_parts(*ip, port), v));
+                }
+            }
+            self.current = None;
+        }
     }
 }
 
@@ -390,14 +1239,211 @@ fn test_shuffling() {
     assert!((h1 as i32 - 2525).abs() < 500);
 }
 
+#[test]
+fn test_port_allocator() {
+    let mut allocator = PortAllocator::with_seed(1000, 1009, 100, 42);
+    let ports: Vec<u16> = (0..10).map(|_| allocator.allocate(0).unwrap()).collect();
+    assert!(allocator.allocate(0).is_none());
+    let mut sorted = ports.clone();
+    sorted.sort();
+    assert_eq!(sorted, (1000..1010).collect::<Vec<u16>>());
+    // seeding is reproducible
+    let mut other = PortAllocator::with_seed(1000, 1009, 100, 42);
+    assert_eq!(ports, (0..10).map(|_| other.allocate(0).unwrap()).collect::<Vec<u16>>());
+
+    assert!(allocator.release(ports[3], 50));
+    assert!(!allocator.release(ports[3], 50));
+    assert!(!allocator.release(999, 50));
+    assert!(allocator.release(ports[5], 60));
+    assert_eq!(allocator.quarantined_ports(), 2);
+    assert!(allocator.allocate(149).is_none());
+    assert_eq!(allocator.allocate(150), Some(ports[3]));
+    assert_eq!(allocator.quarantined_ports(), 1);
+    assert_eq!(allocator.allocate(200), Some(ports[5]));
+    assert_eq!(allocator.free_ports(), 0);
+}
+
+#[test]
+fn test_port_allocator_for_pipeline() {
+    let port_mask = 0xF000;
+    assert_eq!(tcp_port_base(port_mask, 0), 0xF000);
+    assert_eq!(tcp_port_base(port_mask, 1), 0xE000);
+    for pipeline in 0..16u16 {
+        let mut allocator = PortAllocator::for_pipeline(port_mask, pipeline, 0, Some(pipeline as u64));
+        let expected = if pipeline == 15 { 0x0FFF } else { 0x1000 };
+        assert_eq!(allocator.free_ports(), expected);
+        while let Some(port) = allocator.allocate(0) {
+            assert!(port > 0);
+            assert_eq!(port & port_mask, tcp_port_base(port_mask, pipeline));
+        }
+    }
+    // without quarantine released ports are available at once
+    let mut allocator = PortAllocator::for_pipeline(port_mask, 0, 0, None);
+    let port = allocator.allocate(0).unwrap();
+    assert!(allocator.release(port, 0));
+    assert_eq!(allocator.free_ports(), 0x1000);
+}
+
+#[test]
+fn test_histogram() {
+    let mut histogram = Histogram::default();
+    assert_eq!(histogram.percentile(50.0), 0);
+    for v in 1..10001u64 {
+        histogram.record(v);
+    }
+    let percentiles = histogram.percentiles();
+    println!("{}", percentiles);
+    assert_eq!(percentiles.count, 10000);
+    assert_eq!(percentiles.min, 1);
+    assert_eq!(percentiles.max, 10000);
+    assert_eq!(percentiles.mean, 5000);
+    for &(p, expected) in &[
+        (percentiles.p50, 5000),
+        (percentiles.p90, 9000),
+        (percentiles.p99, 9900),
+        (percentiles.p999, 9990),
+    ] {
+        assert!(
+            p >= expected && (p - expected) as f64 <= expected as f64 / 64.0,
+            "{} {}",
+            p,
+            expected
+        );
+    }
+    // small values are exact
+    let mut small = Histogram::default();
+    for v in 0..100u64 {
+        small.record_n(v, 2);
+    }
+    assert_eq!(small.percentile(50.0), 49);
+    assert_eq!(small.percentile(100.0), 99);
+    // huge values
+    small.record(u64::MAX);
+    assert_eq!(small.percentile(100.0), u64::MAX);
+}
+
+#[test]
+fn test_histogram_merge() {
+    let mut h1 = Histogram::new(5);
+    let mut h2 = Histogram::new(5);
+    let mut all = Histogram::new(5);
+    for v in 0..100000u64 {
+        let value = v * v % 1_000_003;
+        if v % 3 == 0 {
+            h1.record(value);
+        } else {
+            h2.record(value);
+        }
+        all.record(value);
+    }
+    h1.merge(&h2);
+    assert_eq!(h1.percentiles(), all.percentiles());
+    h1.reset();
+    assert_eq!(h1.count(), 0);
+    assert_eq!(h1.min(), 0);
+
+    let system_data = SystemData {
+        cpu_clock: 2_000_000_000,
+        topology: Default::default(),
+    };
+    let mut h = Histogram::default();
+    h.record(2000);
+    let durations = h.percentiles().to_durations(&system_data);
+    assert_eq!(durations.p50, Duration::from_micros(1));
+    assert_eq!(durations.max, Duration::from_micros(1));
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("200ms"), Ok(Duration::from_millis(200)));
+    assert_eq!(parse_duration(" 5s "), Ok(Duration::from_secs(5)));
+    assert_eq!(parse_duration("1.5m"), Ok(Duration::from_secs(90)));
+    assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+    assert_eq!(parse_duration("10 us"), Ok(Duration::from_micros(10)));
+    assert_eq!(parse_duration("100ns"), Ok(Duration::from_nanos(100)));
+    assert!(parse_duration("5").is_err());
+    assert!(parse_duration("5d").is_err());
+    assert!(parse_duration("ms").is_err());
+}
+
+#[test]
+fn test_timeouts() {
+    use timer_wheel::{HierarchicalTimerWheel, TimerWheel};
+
+    #[derive(Deserialize)]
+    struct Config {
+        timeouts: Option<Timeouts>,
+    }
+    let config: Config = toml::from_str(
+        r#"
+        [timeouts]
+        established = 300
+        syn_sent = "2s"
+        time_wait = "500ms"
+        "#,
+    )
+    .unwrap();
+    let timeouts = Timeouts::default_or_some(&config.timeouts);
+    assert_eq!(timeouts.for_state(TcpState::Established), Duration::from_millis(300));
+    assert_eq!(timeouts.for_state(TcpState::SynSent), Duration::from_secs(2));
+    assert_eq!(timeouts.for_state(TcpState::Closed), Duration::from_millis(500));
+    assert_eq!(
+        timeouts.for_state(TcpState::SynReceived),
+        Duration::from_millis(DEFAULT_SYN_TIMEOUT)
+    );
+    assert_eq!(
+        timeouts.for_state(TcpState::FinWait2),
+        Duration::from_millis(DEFAULT_FIN_WAIT_TIMEOUT)
+    );
+    assert_eq!(timeouts.established, Some(300));
+    assert_eq!(timeouts.syn_sent, Some(2000));
+    assert_eq!(timeouts.syn_sent_duration(), Some(Duration::from_secs(2)));
+
+    // durations are rounded up to whole milliseconds
+    let config: Config = toml::from_str("[timeouts]\nestablished = \"1500us\"").unwrap();
+    assert_eq!(config.timeouts.unwrap().established, Some(2));
+
+    let config: Config = toml::from_str("").unwrap();
+    assert_eq!(Timeouts::default_or_some(&config.timeouts), Timeouts::default());
+    assert!(toml::from_str::<Config>("[timeouts]\nfin_wait = \"5 parsecs\"").is_err());
+
+    assert_eq!(timeouts.validate(Duration::from_secs(2)), Ok(()));
+    assert_eq!(
+        timeouts.validate(Duration::from_secs(1)),
+        Err(TimeoutTooLarge {
+            name: "syn_sent",
+            timeout: Duration::from_secs(2),
+            max: Duration::from_secs(1),
+        })
+    );
+    let system_data = SystemData {
+        cpu_clock: 1_000_000_000,
+        topology: Default::default(),
+    };
+    // 256 slots of 1 ms
+    let wheel: TimerWheel<u32> = TimerWheel::new(256, 1_000_000, 8);
+    assert!(timeouts
+        .validate_for_wheel(wheel.get_max_timeout_cycles(), &system_data)
+        .is_err());
+    let wheel: TimerWheel<u32> = TimerWheel::new(4096, 1_000_000, 8);
+    assert!(timeouts
+        .validate_for_wheel(wheel.get_max_timeout_cycles(), &system_data)
+        .is_ok());
+    // without limit
+    let wheel: HierarchicalTimerWheel<u32> = HierarchicalTimerWheel::new(256, 1_000_000, 8);
+    assert!(timeouts
+        .validate_for_wheel(wheel.get_max_timeout_cycles(), &system_data)
+        .is_ok());
+}
+
 #[test]
 fn test_chunk_heap() {
     let mut chunk_heap = ChunkHeap::<u16>::new(0);
     let mut port_map = PortMap::new();
     let item: u16 = 4321;
-    port_map.insert(&mut chunk_heap, 1234, item);
-    port_map.insert(&mut chunk_heap, 0, item + 1);
-    port_map.insert(&mut chunk_heap, 0xFFFF, item + 2);
+    port_map.insert(&mut chunk_heap, 1234, item).unwrap();
+    port_map.insert(&mut chunk_heap, 0, item + 1).unwrap();
+    port_map.insert(&mut chunk_heap, 0xFFFF, item + 2).unwrap();
     {
         let val = port_map.get(&mut chunk_heap, 1234).unwrap();
         assert_eq!(*val, item);
@@ -448,3 +1494,172 @@ fn test_sock2index() {
         assert!(not_existent.is_none());
     }
 }
+
+#[test]
+fn test_sock2index_v6() {
+    let mut sock_map = Sock2Index::<SocketAddrV6>::default();
+    let s1 = SocketAddrV6::new("2001:db8::1".parse().unwrap(), 1024, 0, 0);
+    let s2 = SocketAddrV6::new("2001:db8::2".parse().unwrap(), 1024, 0, 0);
+    sock_map.insert(s1, 1);
+    sock_map.insert(s2, 2);
+    assert_eq!(*sock_map.get(&s1).unwrap(), 1);
+    assert_eq!(*sock_map.get(&s2).unwrap(), 2);
+    // flow info and scope id are ignored
+    assert_eq!(*sock_map.get(&SocketAddrV6::new(*s1.ip(), 1024, 7, 1)).unwrap(), 1);
+    assert!(sock_map.get(&SocketAddrV6::new(*s1.ip(), 1025, 0, 0)).is_none());
+    assert_eq!(sock_map.remove(&s1), Some(1));
+    assert!(sock_map.get(&s1).is_none());
+    assert_eq!(sock_map.values(), vec![2]);
+}
+
+#[test]
+fn test_sock2index_mixed() {
+    let mut sock_map = Sock2Index::<SocketAddr>::default();
+    let mut socks = Vec::new();
+    for i in 0..512u16 {
+        // an IPv4 address and its IPv4 mapped IPv6 address are different keys
+        let v4 = Ipv4Addr::new(10, 0, (i % 4) as u8, 1);
+        socks.push(SocketAddr::new(IpAddr::V4(v4), 1000 + i));
+        socks.push(SocketAddr::new(IpAddr::V6(v4.to_ipv6_mapped()), 1000 + i));
+        socks.push(SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, i % 4)),
+            1000 + i,
+        ));
+    }
+    for (i, sock) in socks.iter().enumerate() {
+        sock_map.insert(*sock, i as u16 + 1);
+    }
+    for (i, sock) in socks.iter().enumerate() {
+        assert_eq!(*sock_map.get(sock).unwrap(), i as u16 + 1);
+    }
+    assert_eq!(sock_map.values().len(), socks.len());
+    for sock in socks.iter().filter(|s| s.is_ipv4()) {
+        assert!(sock_map.remove(sock).is_some());
+    }
+    assert_eq!(sock_map.values().len(), 2 * 512);
+    for (i, sock) in socks.iter().enumerate() {
+        assert_eq!(sock_map.get(sock).is_some(), sock.is_ipv6(), "{} {}", i, sock);
+    }
+}
+
+#[test]
+fn test_sock2index_values() {
+    let mut sock_map = Sock2Index::<(u32, u16), u32>::default();
+    sock_map.insert((1, 2), 0);
+    sock_map.insert((1, 3), 1_000_000);
+    assert_eq!(*sock_map.get(&(1, 2)).unwrap(), 0);
+    assert_eq!(*sock_map.get(&(1, 3)).unwrap(), 1_000_000);
+    assert!(sock_map.get(&(1, 4)).is_none());
+    let mut values = sock_map.values();
+    values.sort();
+    assert_eq!(values, vec![0, 1_000_000]);
+    assert_eq!(sock_map.remove(&(1, 2)), Some(0));
+    assert_eq!(sock_map.remove(&(1, 2)), None);
+
+    let mut sock_map = Sock2Index::<SocketAddrV6, usize>::default();
+    let sock = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 80, 0, 0);
+    sock_map.insert(sock, 5_000_000_000usize);
+    assert_eq!(*sock_map.get(&sock).unwrap(), 5_000_000_000usize);
+}
+
+#[test]
+#[should_panic(expected = "EMPTY value cannot be stored")]
+fn test_sock2index_empty_value() {
+    let mut sock_map = Sock2Index::new();
+    sock_map.insert((1, 2), 0);
+}
+
+#[test]
+fn test_sock2index_growth() {
+    // more IP addresses than chunks in the former fixed size heap
+    let mut sock_map = Sock2Index::<(u32, u16), u32>::default();
+    for ip in 0..5000u32 {
+        sock_map.insert((ip, 80), ip);
+        sock_map.insert((ip, 81), ip);
+    }
+    let stats = sock_map.memory_stats();
+    assert_eq!(stats.ips, 5000);
+    assert_eq!(stats.chunks, 5000);
+    assert_eq!(stats.used_chunks, 5000);
+    assert_eq!(stats.used_slots, 10000);
+    assert!(stats.bytes > 5000 * CHUNK_SIZE * 4);
+    for ip in 0..5000u32 {
+        assert_eq!(sock_map.remove(&(ip, 80)), Some(ip));
+        assert_eq!(sock_map.remove(&(ip, 81)), Some(ip));
+    }
+    let stats = sock_map.memory_stats();
+    assert_eq!(stats.ips, 0);
+    assert_eq!(stats.used_chunks, 0);
+    assert_eq!(stats.used_slots, 0);
+    assert!(sock_map.values().is_empty());
+    // released chunks are reused
+    for ip in 0..100u32 {
+        sock_map.insert((ip, 1000), ip);
+    }
+    assert_eq!(sock_map.memory_stats().chunks, 5000);
+    assert_eq!(sock_map.memory_stats().used_chunks, 100);
+}
+
+#[test]
+fn test_sock2index_max_chunks() {
+    let mut sock_map: Sock2Index = Sock2Index::with_max_chunks(2);
+    assert!(sock_map.try_insert((1, 1), 1).is_ok());
+    assert!(sock_map.try_insert((1, 255), 2).is_ok());
+    assert!(sock_map.try_insert((1, 256), 3).is_ok());
+    assert_eq!(sock_map.try_insert((1, 512), 4), Err(OutOfChunks));
+    assert_eq!(sock_map.try_insert((2, 1), 5), Err(OutOfChunks));
+    assert_eq!(sock_map.memory_stats().ips, 1);
+    // the chunk of ports 256..511 becomes empty and is reclaimed
+    assert_eq!(sock_map.remove(&(1, 256)), Some(3));
+    assert!(sock_map.try_insert((2, 1), 5).is_ok());
+    assert_eq!(*sock_map.get(&(2, 1)).unwrap(), 5);
+    assert_eq!(sock_map.memory_stats().used_chunks, 2);
+}
+
+#[test]
+fn test_sock2index_iter() {
+    let mut sock_map = Sock2Index::<(u32, u16), u32>::default();
+    let socks = vec![(1, 1), (1, 255), (1, 256), (1, 0xFFFF), (2, 80), (3, 0x8000)];
+    for (i, sock) in socks.iter().enumerate() {
+        sock_map.insert(*sock, i as u32);
+    }
+    assert_eq!(sock_map.len(), 6);
+    // overwriting does not change the length
+    sock_map.insert((2, 80), 4);
+    assert_eq!(sock_map.len(), 6);
+    let entries: Vec<((u32, u16), u32)> = sock_map.iter().collect();
+    assert_eq!(
+        entries,
+        vec![
+            ((1, 1), 0),
+            ((1, 255), 1),
+            ((1, 256), 2),
+            ((1, 0xFFFF), 3),
+            ((2, 80), 4),
+            ((3, 0x8000), 5)
+        ]
+    );
+    assert_eq!((&sock_map).into_iter().count(), 6);
+
+    sock_map.retain(|sock, v| sock.0 != 1 || *v % 2 == 0);
+    assert_eq!(sock_map.len(), 4);
+    assert!(sock_map.get(&(1, 255)).is_none());
+    assert_eq!(*sock_map.get(&(1, 256)).unwrap(), 2);
+    // the chunk of ports 0xFF00..0xFFFF of ip 1 is released
+    assert_eq!(sock_map.memory_stats().used_chunks, 4);
+
+    let mut drained = sock_map.drain_ip(1);
+    drained.sort();
+    assert_eq!(drained, vec![((1, 1), 0), ((1, 256), 2)]);
+    assert_eq!(sock_map.len(), 2);
+    assert!(sock_map.drain_ip(1).is_empty());
+    assert_eq!(sock_map.memory_stats().ips, 2);
+
+    sock_map.clear();
+    assert!(sock_map.is_empty());
+    assert_eq!(sock_map.iter().count(), 0);
+    let stats = sock_map.memory_stats();
+    assert_eq!((stats.ips, stats.used_chunks, stats.used_slots), (0, 0, 0));
+    sock_map.insert((4, 4), 4);
+    assert_eq!(sock_map.iter().collect::<Vec<_>>(), vec![((4, 4), 4)]);
+}